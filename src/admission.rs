@@ -0,0 +1,184 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::{AcquireError, OwnedSemaphorePermit, Semaphore};
+
+// What to do with requests that arrive while `max_concurrent_requests` are already being answered
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Overflow {
+    // Discard them without a reply, leaving the client to retry
+    Drop,
+    // Hold up to `limit` of them until a slot frees up, and discard any more
+    Queue { limit: usize },
+    // Answer them straight away with REFUSED
+    Refuse,
+}
+
+// Bounds the number of requests being answered at once. Requests are admitted as they're received,
+// before any task is spawned for them, so that a flood of queries can't pile up unbounded work
+pub struct Limiter {
+    semaphore: Arc<Semaphore>,
+    permits: u32,
+    overflow: Overflow,
+    queued: Arc<AtomicUsize>,
+    dropped: AtomicU64,
+    refused: AtomicU64,
+}
+
+pub enum Admission {
+    Admitted(Ticket),
+    Dropped,
+    Refused,
+}
+
+// Entitles a request to be answered, either straight away or once a slot frees up
+pub struct Ticket {
+    semaphore: Arc<Semaphore>,
+    permit: Option<OwnedSemaphorePermit>,
+    queued: Option<Arc<AtomicUsize>>,
+}
+
+impl Ticket {
+    // Waits for a slot, which is held until the returned permit is dropped
+    pub async fn wait(mut self) -> Result<OwnedSemaphorePermit, AcquireError> {
+        match self.permit.take() {
+            Some(permit) => Ok(permit),
+            None => self.semaphore.clone().acquire_owned().await,
+        }
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        if let Some(queued) = &self.queued {
+            queued.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Limiter {
+    pub fn new(max_concurrent_requests: usize, overflow: Overflow) -> Self {
+        // Draining acquires every permit at once, which is limited to `u32::MAX`
+        let permits = max_concurrent_requests.min(u32::MAX as usize) as u32;
+
+        Self {
+            semaphore: Arc::new(Semaphore::new(permits as usize)),
+            permits,
+            overflow,
+            queued: Arc::new(AtomicUsize::new(0)),
+            dropped: AtomicU64::new(0),
+            refused: AtomicU64::new(0),
+        }
+    }
+
+    pub fn admit(&self) -> Admission {
+        if let Ok(permit) = self.semaphore.clone().try_acquire_owned() {
+            return Admission::Admitted(Ticket {
+                semaphore: self.semaphore.clone(),
+                permit: Some(permit),
+                queued: None,
+            });
+        }
+
+        match self.overflow {
+            Overflow::Queue { limit } => {
+                let queued =
+                    self.queued
+                        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |queued| {
+                            (queued < limit).then(|| queued + 1)
+                        });
+
+                if queued.is_ok() {
+                    return Admission::Admitted(Ticket {
+                        semaphore: self.semaphore.clone(),
+                        permit: None,
+                        queued: Some(self.queued.clone()),
+                    });
+                }
+
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                Admission::Dropped
+            }
+            Overflow::Drop => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                Admission::Dropped
+            }
+            Overflow::Refuse => {
+                self.refused.fetch_add(1, Ordering::Relaxed);
+                Admission::Refused
+            }
+        }
+    }
+
+    // The number of requests shed so far, by dropping and by refusing them respectively
+    pub fn shed(&self) -> (u64, u64) {
+        (
+            self.dropped.load(Ordering::Relaxed),
+            self.refused.load(Ordering::Relaxed),
+        )
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.permits as usize - self.semaphore.available_permits()
+    }
+
+    // Resolves once every admitted request, including those queued, has finished. New requests
+    // queued in the meantime are only let in afterwards
+    pub async fn idle(&self) {
+        // Infallible, as the semaphore is never closed
+        let _permits = self.semaphore.acquire_many(self.permits).await.unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::admission::{Admission, Limiter, Overflow};
+
+    fn admitted(admission: Admission) -> bool {
+        matches!(admission, Admission::Admitted(_))
+    }
+
+    #[tokio::test]
+    async fn test_limiter_queue() {
+        let limiter = Limiter::new(1, Overflow::Queue { limit: 1 });
+
+        let Admission::Admitted(first) = limiter.admit() else {
+            panic!("first request wasn't admitted");
+        };
+        let first = first.wait().await.unwrap();
+        assert_eq!(limiter.in_flight(), 1);
+
+        let Admission::Admitted(second) = limiter.admit() else {
+            panic!("second request wasn't queued");
+        };
+        assert!(!admitted(limiter.admit()));
+        assert_eq!(limiter.shed(), (1, 0));
+
+        // The queued request takes the first's slot once it's done
+        drop(first);
+        let second = second.wait().await.unwrap();
+        assert_eq!(limiter.in_flight(), 1);
+
+        // Leaving the queue makes room for another request
+        assert!(admitted(limiter.admit()));
+
+        drop(second);
+        assert_eq!(limiter.in_flight(), 0);
+        limiter.idle().await;
+    }
+
+    #[test]
+    fn test_limiter_refuse() {
+        let limiter = Limiter::new(2, Overflow::Refuse);
+
+        let _first = limiter.admit();
+        let _second = limiter.admit();
+        assert!(matches!(limiter.admit(), Admission::Refused));
+        assert!(matches!(limiter.admit(), Admission::Refused));
+        assert_eq!(limiter.shed(), (0, 2));
+
+        let limiter = Limiter::new(0, Overflow::Drop);
+        assert!(matches!(limiter.admit(), Admission::Dropped));
+        assert_eq!(limiter.shed(), (1, 0));
+    }
+}
@@ -0,0 +1,414 @@
+use crate::protocol::{Message, Name, RecordClass, RecordType, ResponseCode};
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// A question's key, except that NXDOMAIN replies apply to every type of record, so they're cached
+// with no type
+type Key = (Name, Option<RecordType>, RecordClass);
+
+// A cache of upstream replies keyed by their question. Both answers and negative replies (RFC 2308)
+// are cached: the former until the shortest TTL among their records has elapsed, and the latter for
+// as long as the SOA record in their authority section allows. Entries are also evicted, least
+// recently used first, once the cache holds more than `max_entries` replies or more than
+// `max_bytes` bytes' worth of them
+pub struct Cache {
+    max_entries: usize,
+    max_bytes: usize,
+    state: Mutex<State>,
+}
+
+struct State {
+    entries: HashMap<Key, Entry>,
+    // Maps the tick at which each entry was last used to its key, so that the least recently used
+    // entry is always the first
+    recency: BTreeMap<u64, Key>,
+    tick: u64,
+    bytes: usize,
+}
+
+struct Entry {
+    reply: Message,
+    size: usize,
+    inserted: Instant,
+    expires: Instant,
+    last_used: u64,
+}
+
+impl Cache {
+    pub fn new(max_entries: usize, max_bytes: usize) -> Self {
+        Self {
+            max_entries,
+            max_bytes,
+            state: Mutex::new(State {
+                entries: HashMap::new(),
+                recency: BTreeMap::new(),
+                tick: 0,
+                bytes: 0,
+            }),
+        }
+    }
+
+    // Returns the cached reply to the query's question, adapted to the query, if there is one that
+    // hasn't yet expired
+    pub fn get(&self, query: &Message, now: Instant) -> Option<Message> {
+        let (name, type_, class) = query.question_key()?;
+
+        let mut state = self.state.lock().unwrap();
+
+        let keys = [
+            (name.clone(), Some(type_), class.clone()),
+            (name, None, class),
+        ];
+
+        keys.into_iter().find_map(|key| {
+            state
+                .get(&key, now)
+                .map(|(reply, age)| reply.reuse_for(query, age))
+        })
+    }
+
+    // Caches a reply received from the upstream, if it's a complete answer to the query's question,
+    // or a negative reply carrying an SOA record. `size` is the length of the reply on the wire,
+    // which approximates the memory it occupies
+    pub fn insert(&self, query: &Message, reply: &Message, size: usize, now: Instant) {
+        let key = match query.question_key() {
+            Some((name, type_, class)) => (name, Some(type_), class),
+            None => return,
+        };
+
+        let flags = reply.flags();
+
+        if !flags.is_reply() || flags.is_truncated() {
+            return;
+        }
+
+        let (key, ttl, is_negative) = match flags.response_code() {
+            ResponseCode::NO_ERROR if !reply.answers().is_empty() => (key, reply.min_ttl(), false),
+            // NODATA: the name exists, but has no records of the queried type
+            ResponseCode::NO_ERROR => (key, reply.negative_ttl(), true),
+            // An NXDOMAIN following a CNAME chain is about the chain's target, rather than the name
+            // queried (RFC 2308 section 2.1), so it's only known to answer the question itself
+            ResponseCode::NX_DOMAIN if !reply.answers().is_empty() => {
+                (key, reply.negative_ttl(), true)
+            }
+            ResponseCode::NX_DOMAIN => {
+                let (name, _, class) = key;
+                ((name, None, class), reply.negative_ttl(), true)
+            }
+            _ => return,
+        };
+
+        let ttl = match ttl {
+            Some(ttl) if ttl > 0 => ttl,
+            _ => return,
+        };
+
+        // The SOA record's TTL may exceed the negative TTL; it's capped so that clients see the
+        // remaining negative TTL count down, just as they would for an answer
+        let mut reply = reply.clone();
+        if is_negative {
+            reply.clamp_ttls(ttl);
+        }
+
+        if size > self.max_bytes {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+
+        state.remove(&key);
+
+        while !state.entries.is_empty()
+            && (state.entries.len() >= self.max_entries || state.bytes + size > self.max_bytes)
+        {
+            state.remove_least_recently_used();
+        }
+
+        if self.max_entries == 0 {
+            return;
+        }
+
+        let entry = Entry {
+            reply,
+            size,
+            inserted: now,
+            expires: now + Duration::from_secs(ttl as u64),
+            last_used: 0,
+        };
+
+        state.bytes += size;
+        state.entries.insert(key.clone(), entry);
+        state.touch(&key);
+    }
+}
+
+impl State {
+    // Returns the entry's reply and its age in seconds, or evicts it if it has expired
+    fn get(&mut self, key: &Key, now: Instant) -> Option<(&Message, u32)> {
+        let entry = self.entries.get(key)?;
+
+        if entry.expires <= now {
+            self.remove(key);
+            return None;
+        }
+
+        self.touch(key);
+
+        let entry = &self.entries[key];
+        let age = now.duration_since(entry.inserted).as_secs() as u32;
+
+        Some((&entry.reply, age))
+    }
+
+    fn touch(&mut self, key: &Key) {
+        self.tick += 1;
+        let tick = self.tick;
+
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.recency.remove(&entry.last_used);
+            entry.last_used = tick;
+            self.recency.insert(tick, key.clone());
+        }
+    }
+
+    fn remove(&mut self, key: &Key) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.last_used);
+            self.bytes -= entry.size;
+        }
+    }
+
+    fn remove_least_recently_used(&mut self) {
+        if let Some((_, key)) = self.recency.pop_first() {
+            if let Some(entry) = self.entries.remove(&key) {
+                self.bytes -= entry.size;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cache::Cache;
+    use crate::protocol::Message;
+    use crate::test_util::{query, A};
+
+    use std::time::{Duration, Instant};
+
+    const MX: u16 = 15;
+
+    // An A record reply for the query, with the owner name compressed against the question
+    fn reply(id: u16, name: &str, ttl: u32) -> Vec<u8> {
+        let mut bytes = query(id, name, A);
+        bytes[2] = 0x81;
+        bytes[3] = 0x80;
+        bytes[7] = 0x01;
+        bytes.extend([0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01]);
+        bytes.extend(ttl.to_be_bytes());
+        bytes.extend([0x00, 0x04, 0x0a, 0x00, 0x00, 0x01]);
+        bytes
+    }
+
+    // A reply with no answers and an SOA record for the query's name in its authority section
+    fn negative_reply(
+        name: &str,
+        type_: u16,
+        response_code: u8,
+        ttl: u32,
+        minimum: u32,
+    ) -> Vec<u8> {
+        let mut bytes = query(1, name, type_);
+        bytes[2] = 0x81;
+        bytes[3] = 0x80 | response_code;
+        bytes[9] = 0x01;
+        bytes.extend([0xc0, 0x0c, 0x00, 0x06, 0x00, 0x01]);
+        bytes.extend(ttl.to_be_bytes());
+        bytes.extend([0x00, 0x16, 0x00, 0x00]);
+        for field in [1, 7200, 3600, 1209600, minimum] {
+            bytes.extend(u32::to_be_bytes(field));
+        }
+        bytes
+    }
+
+    // An NXDOMAIN reply whose answer section holds a CNAME from the query's name to `x.invalid`
+    fn cname_nxdomain_reply(name: &str) -> Vec<u8> {
+        let negative = negative_reply(name, A, 3, 900, 60);
+        let question_end = 12 + name.len() + 2 + 4;
+
+        let mut bytes = negative[0..question_end].to_vec();
+        bytes[7] = 0x01;
+        bytes.extend([
+            0xc0, 0x0c, 0x00, 0x05, 0x00, 0x01, 0x00, 0x00, 0x03, 0x84, 0x00, 0x0b,
+        ]);
+        bytes.extend([
+            0x01, b'x', 0x07, b'i', b'n', b'v', b'a', b'l', b'i', b'd', 0x00,
+        ]);
+        bytes.extend(&negative[question_end..]);
+        bytes
+    }
+
+    fn insert(cache: &Cache, name: &str, ttl: u32, now: Instant) {
+        insert_reply(cache, name, A, &reply(1, name, ttl), now);
+    }
+
+    fn insert_reply(cache: &Cache, name: &str, type_: u16, reply: &[u8], now: Instant) {
+        let query = Message::parse(&query(1, name, type_)).unwrap();
+        cache.insert(&query, &Message::parse(reply).unwrap(), reply.len(), now);
+    }
+
+    fn get(cache: &Cache, id: u16, name: &str, now: Instant) -> Option<Message> {
+        get_type(cache, id, name, A, now)
+    }
+
+    fn get_type(cache: &Cache, id: u16, name: &str, type_: u16, now: Instant) -> Option<Message> {
+        cache.get(&Message::parse(&query(id, name, type_)).unwrap(), now)
+    }
+
+    #[test]
+    fn test_cache_hit() {
+        let cache = Cache::new(16, 4096);
+        let now = Instant::now();
+
+        insert(&cache, "xkcd.com", 300, now);
+
+        let hit = get(&cache, 0xabcd, "xkcd.com", now + Duration::from_secs(100)).unwrap();
+        assert_eq!(
+            hit,
+            Message::parse(&reply(0xabcd, "xkcd.com", 200)).unwrap()
+        );
+
+        let hit = get(&cache, 0xabcd, "XKCD.com", now).unwrap();
+        assert_eq!(hit.question().unwrap().name().to_string(), "XKCD.com");
+
+        assert!(get(&cache, 1, "xkcd.net", now).is_none());
+    }
+
+    #[test]
+    fn test_cache_dotted_label() {
+        let cache = Cache::new(16, 4096);
+        let now = Instant::now();
+
+        // Swaps the hyphens for dots within the labels, as names given as text can't hold them
+        let dotted = |bytes: Vec<u8>| {
+            bytes
+                .into_iter()
+                .map(|byte| if byte == b'-' { b'.' } else { byte })
+                .collect::<Vec<_>>()
+        };
+
+        insert(&cache, "a.b.com", 300, now);
+
+        let query = Message::parse(&dotted(query(1, "a-b.com", A))).unwrap();
+        assert!(cache.get(&query, now).is_none());
+
+        let dotted_reply = dotted(reply(1, "a-b.com", 60));
+        let parsed_reply = Message::parse(&dotted_reply).unwrap();
+        cache.insert(&query, &parsed_reply, dotted_reply.len(), now);
+
+        assert_eq!(cache.get(&query, now).unwrap(), parsed_reply);
+        assert_eq!(
+            get(&cache, 1, "a.b.com", now).unwrap(),
+            Message::parse(&reply(1, "a.b.com", 300)).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_cache_expiry() {
+        let cache = Cache::new(16, 4096);
+        let now = Instant::now();
+
+        insert(&cache, "xkcd.com", 300, now);
+        insert(&cache, "xkcd.net", 0, now);
+
+        assert!(get(&cache, 1, "xkcd.com", now + Duration::from_secs(299)).is_some());
+        assert!(get(&cache, 1, "xkcd.com", now + Duration::from_secs(300)).is_none());
+        assert!(get(&cache, 1, "xkcd.net", now).is_none());
+    }
+
+    #[test]
+    fn test_cache_eviction() {
+        let now = Instant::now();
+
+        let cache = Cache::new(2, 4096);
+        insert(&cache, "a.com", 300, now);
+        insert(&cache, "b.com", 300, now);
+        assert!(get(&cache, 1, "a.com", now).is_some());
+        insert(&cache, "c.com", 300, now);
+        assert!(get(&cache, 1, "a.com", now).is_some());
+        assert!(get(&cache, 1, "b.com", now).is_none());
+        assert!(get(&cache, 1, "c.com", now).is_some());
+
+        let size = reply(1, "a.com", 300).len();
+        let cache = Cache::new(16, 2 * size);
+        insert(&cache, "a.com", 300, now);
+        insert(&cache, "b.com", 300, now);
+        insert(&cache, "c.com", 300, now);
+        assert!(get(&cache, 1, "a.com", now).is_none());
+        assert!(get(&cache, 1, "b.com", now).is_some());
+        assert!(get(&cache, 1, "c.com", now).is_some());
+    }
+
+    #[test]
+    fn test_cache_nxdomain() {
+        let cache = Cache::new(16, 4096);
+        let now = Instant::now();
+
+        insert_reply(
+            &cache,
+            "xkcd.bad",
+            A,
+            &negative_reply("xkcd.bad", A, 3, 900, 60),
+            now,
+        );
+
+        // The name doesn't exist, so neither do records of any other type
+        let hit = get_type(&cache, 7, "xkcd.bad", MX, now + Duration::from_secs(10)).unwrap();
+        assert_eq!(hit.flags().response_code().to_string(), "NXDomain (3)");
+        assert_eq!(hit.question().unwrap().type_().to_string(), "MX (15)");
+        assert_eq!(hit.negative_ttl(), Some(50));
+
+        // The SOA minimum bounds the negative TTL
+        assert!(get(&cache, 7, "xkcd.bad", now + Duration::from_secs(60)).is_none());
+
+        // When the NXDOMAIN is for the target of a CNAME, the name queried does exist, so only the
+        // question asked is answered from the cache
+        insert_reply(
+            &cache,
+            "xkcd.com",
+            A,
+            &cname_nxdomain_reply("xkcd.com"),
+            now,
+        );
+
+        let hit = get(&cache, 7, "xkcd.com", now).unwrap();
+        assert_eq!(hit.flags().response_code().to_string(), "NXDomain (3)");
+        assert_eq!(hit.answers().len(), 1);
+        assert!(get_type(&cache, 7, "xkcd.com", MX, now).is_none());
+    }
+
+    #[test]
+    fn test_cache_nodata() {
+        let cache = Cache::new(16, 4096);
+        let now = Instant::now();
+
+        insert_reply(
+            &cache,
+            "xkcd.com",
+            MX,
+            &negative_reply("xkcd.com", MX, 0, 30, 3600),
+            now,
+        );
+
+        let hit = get_type(&cache, 7, "xkcd.com", MX, now).unwrap();
+        assert_eq!(hit.flags().response_code().to_string(), "NoError (0)");
+        assert!(hit.answers().is_empty());
+
+        // Only the queried type is known not to exist
+        assert!(get(&cache, 7, "xkcd.com", now).is_none());
+
+        // The SOA TTL bounds the negative TTL too
+        assert!(get_type(&cache, 7, "xkcd.com", MX, now + Duration::from_secs(30)).is_none());
+    }
+}
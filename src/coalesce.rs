@@ -0,0 +1,180 @@
+use crate::protocol::{Message, QuestionKey};
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::future::Future;
+use std::sync::Mutex;
+
+use tokio::sync::oneshot::{channel, Sender};
+
+// Errors are shared with every waiting query, so they're passed around as strings
+type Outcome = Result<Message, String>;
+
+// Deduplicates concurrent queries for the same question, so that only the first of them is sent
+// upstream, and the rest wait for its reply rather than sending their own
+pub struct Coalescer {
+    in_flight: Mutex<HashMap<QuestionKey, Vec<Sender<Outcome>>>>,
+}
+
+// Removes the in-flight entry once the first query's exchange completes or is abandoned; in the
+// latter case, the waiting queries are told so by their channels closing
+struct InFlightGuard<'a> {
+    coalescer: &'a Coalescer,
+    key: QuestionKey,
+}
+
+impl InFlightGuard<'_> {
+    fn finish(self) -> Vec<Sender<Outcome>> {
+        self.coalescer
+            .in_flight
+            .lock()
+            .unwrap()
+            .remove(&self.key)
+            .unwrap_or_default()
+    }
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.coalescer.in_flight.lock().unwrap().remove(&self.key);
+    }
+}
+
+impl Coalescer {
+    pub fn new() -> Self {
+        Self {
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Runs `exchange` to obtain the reply to the query, unless an identical question is already in
+    // flight, in which case that exchange's reply is shared, adapted to carry this query's ID
+    pub async fn coalesce<F>(
+        &self,
+        query: &Message,
+        exchange: F,
+    ) -> Result<(Message, Vec<u8>), Box<dyn Error + Send + Sync>>
+    where
+        F: Future<Output = Result<(Message, Vec<u8>), Box<dyn Error + Send + Sync>>>,
+    {
+        let key = match query.question_key() {
+            Some(key) => key,
+            None => return exchange.await,
+        };
+
+        let receiver = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+
+            match in_flight.get_mut(&key) {
+                Some(waiting) => {
+                    let (sender, receiver) = channel();
+                    waiting.push(sender);
+                    Some(receiver)
+                }
+                None => {
+                    in_flight.insert(key.clone(), vec![]);
+                    None
+                }
+            }
+        };
+
+        if let Some(receiver) = receiver {
+            let reply = receiver
+                .await
+                .map_err(|_| "Coalesced DNS query was abandoned")??
+                .reuse_for(query, 0);
+            let reply_bytes = reply.serialize();
+
+            return Ok((reply, reply_bytes));
+        }
+
+        let guard = InFlightGuard {
+            coalescer: self,
+            key,
+        };
+
+        let result = exchange.await;
+
+        for sender in guard.finish() {
+            let outcome = match &result {
+                Ok((reply, _)) => Ok(reply.clone()),
+                Err(error) => Err(error.to_string()),
+            };
+
+            // Fails only if the waiting query has been abandoned in the meantime
+            let _ = sender.send(outcome);
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::coalesce::Coalescer;
+    use crate::protocol::{Message, ResponseCode};
+    use crate::test_util;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use tokio::sync::Notify;
+
+    fn query(id: u16, name: &str) -> Message {
+        Message::parse(&test_util::query(id, name, test_util::A)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_coalesce() {
+        let coalescer = Arc::new(Coalescer::new());
+        let exchanges = Arc::new(AtomicUsize::new(0));
+        let release = Arc::new(Notify::new());
+
+        let queries = [
+            (1, "xkcd.com"),
+            (2, "XKCD.com"),
+            (3, "xkcd.com"),
+            (4, "xkcd.net"),
+        ];
+
+        let tasks = queries
+            .into_iter()
+            .map(|(id, name)| {
+                let coalescer = coalescer.clone();
+                let exchanges = exchanges.clone();
+                let release = release.clone();
+
+                tokio::spawn(async move {
+                    let query = query(id, name);
+
+                    let exchange = async {
+                        exchanges.fetch_add(1, Ordering::SeqCst);
+                        release.notified().await;
+
+                        let reply = query.error_reply(ResponseCode::NO_ERROR);
+                        let reply_bytes = reply.serialize();
+                        Ok((reply, reply_bytes))
+                    };
+
+                    coalescer.coalesce(&query, exchange).await.unwrap()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // Every query is waiting once the exchanges for both questions have started
+        while exchanges.load(Ordering::SeqCst) < 2 {
+            tokio::task::yield_now().await;
+        }
+        release.notify_waiters();
+
+        for (task, (id, name)) in tasks.into_iter().zip(queries) {
+            let (reply, reply_bytes) = task.await.unwrap();
+
+            assert!(reply.is_reply_to(&query(id, name)));
+            assert_eq!(Message::parse(&reply_bytes).unwrap(), reply);
+        }
+
+        // One exchange per distinct question
+        assert_eq!(exchanges.load(Ordering::SeqCst), 2);
+    }
+}
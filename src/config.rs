@@ -0,0 +1,886 @@
+use crate::admission::Overflow;
+use crate::dnstap::{DnstapConfig, DnstapOutput};
+use crate::fixture::UpstreamMode;
+use crate::matcher::Matcher;
+use crate::protocol::{Name, Record};
+use crate::query_log::{LogFormat, QueryLogConfig};
+use crate::server::Config;
+use crate::upstream::{Strategy, Upstream};
+
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::fs::read_to_string;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+use regex::Regex;
+use toml::value::Table;
+use toml::Value;
+
+// How many requests may be queued by the `queue` overflow policy, unless configured otherwise
+const DEFAULT_QUEUE_LIMIT: usize = 1000;
+
+// How often the `fastest` strategy reprobes slower upstreams, unless configured otherwise
+const DEFAULT_REPROBE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Names the offending key by its path from the root of the file, e.g. `rules[2].records[0]`; errors
+// in the TOML syntax itself carry no key, but their message gives the line and column instead
+#[derive(Debug)]
+pub struct ConfigError {
+    key: String,
+    message: String,
+}
+
+impl ConfigError {
+    fn new(key: &str, message: impl Into<String>) -> Self {
+        Self {
+            key: key.to_string(),
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        if self.key.is_empty() {
+            write!(fmt, "Invalid configuration: {}", self.message)
+        } else {
+            write!(
+                fmt,
+                "Invalid configuration for `{}`: {}",
+                self.key, self.message
+            )
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+pub fn load(path: &str) -> Result<Config, Box<dyn Error + Send + Sync>> {
+    let text = read_to_string(path)
+        .map_err(|error| format!("Error reading configuration file {}: {}", path, error))?;
+
+    Ok(parse(&text)?)
+}
+
+// Any key left out of the file keeps its default value; unknown keys are rejected, so that typos
+// don't go unnoticed
+pub fn parse(text: &str) -> Result<Config, ConfigError> {
+    let root = match text.parse::<Value>() {
+        Ok(Value::Table(root)) => root,
+        Ok(_) => return Err(ConfigError::new("", "expected a table")),
+        Err(error) => return Err(ConfigError::new("", error.to_string())),
+    };
+
+    let mut config = Config::default();
+
+    let mut strategy = None;
+    let mut reprobe_interval = None;
+    let mut overflow = None;
+    let mut queue_limit = None;
+    let mut mode = None;
+    let mut fixture = None;
+
+    for (key, value) in root {
+        let key = key.as_str();
+
+        match key {
+            "bind_address" => config.bind_address = socket_address(key, value)?,
+            "upstreams" => {
+                config.upstreams = array(key, value)?
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, value)| upstream(&format!("{}[{}]", key, index), value))
+                    .collect::<Result<_, _>>()?;
+
+                if config.upstreams.is_empty() {
+                    return Err(ConfigError::new(key, "at least one upstream is required"));
+                }
+            }
+            "upstream_strategy" => strategy = Some(string(key, value)?),
+            "upstream_mode" => mode = Some(string(key, value)?),
+            "upstream_fixture" => fixture = Some(string(key, value)?),
+            "upstream_reprobe_interval" => reprobe_interval = Some(duration(key, value)?),
+            "egress_address" => config.egress_address = socket_address(key, value)?,
+            "egress_sockets" => config.egress_sockets = positive_integer(key, value)?,
+            "max_packet_size" => {
+                config.max_packet_size = integer(key, value)?;
+
+                if config.max_packet_size < 512 {
+                    return Err(ConfigError::new(key, "must be at least 512"));
+                }
+            }
+            "read_timeout" => config.read_timeout = duration(key, value)?,
+            "write_timeout" => config.write_timeout = duration(key, value)?,
+            "request_timeout" => config.request_timeout = duration(key, value)?,
+            "drain_timeout" => config.drain_timeout = duration(key, value)?,
+            "max_concurrent_requests" => {
+                config.max_concurrent_requests = positive_integer(key, value)?
+            }
+            "overflow" => overflow = Some(string(key, value)?),
+            "overflow_queue_limit" => queue_limit = Some(integer(key, value)?),
+            "max_tcp_connections" => config.max_tcp_connections = positive_integer(key, value)?,
+            "cache_max_entries" => config.cache_max_entries = integer(key, value)?,
+            "cache_max_bytes" => config.cache_max_bytes = integer(key, value)?,
+            "metrics_address" => config.metrics_address = Some(socket_address(key, value)?),
+            "pcap_path" => config.pcap_path = Some(string(key, value)?),
+            "query_log" => config.query_log = Some(query_log(key, value)?),
+            "dnstap" => config.dnstap = Some(dnstap(key, value)?),
+            "rules" => {
+                config.rules = array(key, value)?
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, value)| rule(&format!("{}[{}]", key, index), value))
+                    .collect::<Result<_, _>>()?
+            }
+            _ => return Err(ConfigError::new(key, "unknown key")),
+        }
+    }
+
+    config.upstream_strategy = match strategy.as_deref() {
+        None | Some("ordered") => Strategy::Ordered,
+        Some("round_robin") => Strategy::RoundRobin,
+        Some("weighted_random") => Strategy::WeightedRandom,
+        Some("fastest") => Strategy::Fastest {
+            reprobe_interval: reprobe_interval.unwrap_or(DEFAULT_REPROBE_INTERVAL),
+        },
+        Some(strategy) => {
+            return Err(ConfigError::new(
+                "upstream_strategy",
+                format!(
+                    "unknown strategy `{}`; expected `ordered`, `round_robin`, `weighted_random` \
+                     or `fastest`",
+                    strategy
+                ),
+            ))
+        }
+    };
+
+    if reprobe_interval.is_some() && !matches!(config.upstream_strategy, Strategy::Fastest { .. }) {
+        return Err(ConfigError::new(
+            "upstream_reprobe_interval",
+            "only applies to the `fastest` strategy",
+        ));
+    }
+
+    config.overflow = match overflow.as_deref() {
+        None | Some("queue") => Overflow::Queue {
+            limit: queue_limit.unwrap_or(DEFAULT_QUEUE_LIMIT),
+        },
+        Some("drop") => Overflow::Drop,
+        Some("refuse") => Overflow::Refuse,
+        Some(overflow) => {
+            return Err(ConfigError::new(
+                "overflow",
+                format!(
+                    "unknown policy `{}`; expected `queue`, `drop` or `refuse`",
+                    overflow
+                ),
+            ))
+        }
+    };
+
+    if queue_limit.is_some() && !matches!(config.overflow, Overflow::Queue { .. }) {
+        return Err(ConfigError::new(
+            "overflow_queue_limit",
+            "only applies to the `queue` policy",
+        ));
+    }
+
+    config.upstream_mode = match (mode.as_deref(), fixture) {
+        (None | Some("network"), None) => UpstreamMode::Network,
+        (None | Some("network"), Some(_)) => {
+            return Err(ConfigError::new(
+                "upstream_fixture",
+                "only applies to the `record` and `replay` modes",
+            ))
+        }
+        (Some("record"), Some(fixture)) => UpstreamMode::Record { fixture },
+        (Some("replay"), Some(fixture)) => UpstreamMode::Replay { fixture },
+        (Some("record" | "replay"), None) => {
+            return Err(ConfigError::new(
+                "upstream_mode",
+                "requires `upstream_fixture` to be given",
+            ))
+        }
+        (Some(mode), _) => {
+            return Err(ConfigError::new(
+                "upstream_mode",
+                format!(
+                    "unknown mode `{}`; expected `network`, `record` or `replay`",
+                    mode
+                ),
+            ))
+        }
+    };
+
+    Ok(config)
+}
+
+// Upstreams are given as tables, e.g. `{ address = "8.8.8.8:53", timeout = 2, weight = 1 }`, of
+// which only the address is required
+fn upstream(key: &str, value: Value) -> Result<Upstream, ConfigError> {
+    let mut table = table(key, value)?;
+
+    let address = match table.remove("address") {
+        Some(value) => upstream_address(&format!("{}.address", key), value)?,
+        None => return Err(ConfigError::new(key, "missing key `address`")),
+    };
+
+    let mut upstream = Upstream::new(address, Duration::from_secs(2));
+
+    for (field, value) in table {
+        let key = format!("{}.{}", key, field);
+
+        match field.as_str() {
+            "timeout" => upstream.timeout = duration(&key, value)?,
+            "weight" => {
+                upstream.weight = u32::try_from(integer(&key, value)?)
+                    .map_err(|_| ConfigError::new(&key, "weight is too large"))?
+            }
+            _ => return Err(ConfigError::new(&key, "unknown key")),
+        }
+    }
+
+    Ok(upstream)
+}
+
+fn query_log(key: &str, value: Value) -> Result<QueryLogConfig, ConfigError> {
+    let mut table = table(key, value)?;
+
+    let path = match table.remove("path") {
+        Some(value) => string(&format!("{}.path", key), value)?,
+        None => return Err(ConfigError::new(key, "missing key `path`")),
+    };
+
+    let mut query_log = QueryLogConfig::new(path);
+
+    for (field, value) in table {
+        let key = format!("{}.{}", key, field);
+
+        match field.as_str() {
+            "format" => {
+                query_log.format = match string(&key, value)?.as_str() {
+                    "json" => LogFormat::Json,
+                    "binary" => LogFormat::Binary,
+                    format => {
+                        return Err(ConfigError::new(
+                            &key,
+                            format!("unknown format `{}`; expected `json` or `binary`", format),
+                        ))
+                    }
+                }
+            }
+            "max_bytes" => query_log.max_bytes = Some(positive_integer(&key, value)? as u64),
+            "rotate_interval" => query_log.rotate_interval = Some(duration(&key, value)?),
+            "keep" => query_log.keep = integer(&key, value)?,
+            _ => return Err(ConfigError::new(&key, "unknown key")),
+        }
+    }
+
+    Ok(query_log)
+}
+
+// Events are written to exactly one of a `file`, or a Unix `socket` on which a collector listens
+fn dnstap(key: &str, value: Value) -> Result<DnstapConfig, ConfigError> {
+    let mut output = None;
+    let mut identity = None;
+
+    for (field, value) in table(key, value)? {
+        let field_key = format!("{}.{}", key, field);
+
+        let field_output = match field.as_str() {
+            "file" => DnstapOutput::File(string(&field_key, value)?),
+            "socket" => DnstapOutput::UnixSocket(string(&field_key, value)?),
+            "identity" => {
+                identity = Some(string(&field_key, value)?);
+                continue;
+            }
+            _ => return Err(ConfigError::new(&field_key, "unknown key")),
+        };
+
+        if output.replace(field_output).is_some() {
+            return Err(ConfigError::new(
+                key,
+                "only one of the keys `file` or `socket` may be given",
+            ));
+        }
+    }
+
+    match output {
+        Some(output) => Ok(DnstapConfig { output, identity }),
+        None => Err(ConfigError::new(
+            key,
+            "one of the keys `file` or `socket` is required",
+        )),
+    }
+}
+
+// Rules are given as tables with exactly one of the keys `exact`, `wildcard`, `set` or `regex`
+// choosing how they match, and the records to answer with, e.g.
+// `{ wildcard = "*.example.com", include_apex = true, records = ["example.com 60 IN A 10.0.0.1"] }`
+fn rule(key: &str, value: Value) -> Result<(Matcher, Vec<Record>), ConfigError> {
+    let mut table = table(key, value)?;
+
+    let records = match table.remove("records") {
+        Some(value) => {
+            let records_key = format!("{}.records", key);
+
+            array(&records_key, value)?
+                .into_iter()
+                .enumerate()
+                .map(|(index, value)| {
+                    let key = format!("{}[{}]", records_key, index);
+                    let record = string(&key, value)?;
+                    Record::from_str(&record).map_err(|error| ConfigError::new(&key, error))
+                })
+                .collect::<Result<Vec<_>, _>>()?
+        }
+        None => return Err(ConfigError::new(key, "missing key `records`")),
+    };
+
+    let include_apex = match table.remove("include_apex") {
+        Some(value) => Some(boolean(&format!("{}.include_apex", key), value)?),
+        None => None,
+    };
+
+    let mut matchers = table.into_iter().map(|(field, value)| {
+        let key = format!("{}.{}", key, field);
+
+        let matcher = match field.as_str() {
+            "exact" => Matcher::Exact {
+                name: name(&key, value)?,
+            },
+            "wildcard" => Matcher::Wildcard {
+                pattern: wildcard(&key, value)?,
+                include_apex: include_apex.unwrap_or(false),
+            },
+            "set" => Matcher::Set {
+                names: array(&key, value)?
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, value)| name(&format!("{}[{}]", key, index), value))
+                    .collect::<Result<HashSet<_>, _>>()?,
+            },
+            "regex" => {
+                let regex = string(&key, value)?;
+                Matcher::Regex {
+                    regex: Regex::new(&regex)
+                        .map_err(|error| ConfigError::new(&key, error.to_string()))?,
+                }
+            }
+            _ => return Err(ConfigError::new(&key, "unknown key")),
+        };
+
+        Ok(matcher)
+    });
+
+    let matcher = match (matchers.next(), matchers.next()) {
+        (Some(matcher), None) => matcher?,
+        (None, _) => {
+            return Err(ConfigError::new(
+                key,
+                "missing one of the keys `exact`, `wildcard`, `set` or `regex`",
+            ))
+        }
+        (Some(_), Some(_)) => {
+            return Err(ConfigError::new(
+                key,
+                "only one of the keys `exact`, `wildcard`, `set` or `regex` may be given",
+            ))
+        }
+    };
+
+    if include_apex.is_some() && !matches!(matcher, Matcher::Wildcard { .. }) {
+        return Err(ConfigError::new(
+            &format!("{}.include_apex", key),
+            "only applies to `wildcard` rules",
+        ));
+    }
+
+    Ok((matcher, records))
+}
+
+// Renders the configuration in the form read by `parse`. Rules can't be rendered, as matchers don't
+// keep the text they were built from, so an example is given in a comment instead
+pub fn to_toml(config: &Config) -> String {
+    let (strategy, reprobe_interval) = match config.upstream_strategy {
+        Strategy::Ordered => ("ordered", None),
+        Strategy::RoundRobin => ("round_robin", None),
+        Strategy::WeightedRandom => ("weighted_random", None),
+        Strategy::Fastest { reprobe_interval } => ("fastest", Some(reprobe_interval)),
+    };
+
+    let (overflow, queue_limit) = match config.overflow {
+        Overflow::Drop => ("drop", None),
+        Overflow::Queue { limit } => ("queue", Some(limit)),
+        Overflow::Refuse => ("refuse", None),
+    };
+
+    let (mode, fixture) = match &config.upstream_mode {
+        UpstreamMode::Network => ("network", None),
+        UpstreamMode::Record { fixture } => ("record", Some(fixture)),
+        UpstreamMode::Replay { fixture } => ("replay", Some(fixture)),
+    };
+
+    let mut text = String::new();
+
+    let mut line = |key: &str, value: Value| text.push_str(&format!("{} = {}\n", key, value));
+
+    line("bind_address", Value::from(config.bind_address.as_str()));
+    line("upstream_strategy", Value::from(strategy));
+    if let Some(reprobe_interval) = reprobe_interval {
+        line("upstream_reprobe_interval", seconds(reprobe_interval));
+    }
+    line("upstream_mode", Value::from(mode));
+    if let Some(fixture) = fixture {
+        line("upstream_fixture", Value::from(fixture.as_str()));
+    }
+    line(
+        "egress_address",
+        Value::from(config.egress_address.as_str()),
+    );
+    line("egress_sockets", Value::from(config.egress_sockets as i64));
+    line(
+        "max_packet_size",
+        Value::from(config.max_packet_size as i64),
+    );
+    line("read_timeout", seconds(config.read_timeout));
+    line("write_timeout", seconds(config.write_timeout));
+    line("request_timeout", seconds(config.request_timeout));
+    line("drain_timeout", seconds(config.drain_timeout));
+    line(
+        "max_concurrent_requests",
+        Value::from(config.max_concurrent_requests as i64),
+    );
+    line("overflow", Value::from(overflow));
+    if let Some(queue_limit) = queue_limit {
+        line("overflow_queue_limit", Value::from(queue_limit as i64));
+    }
+    line(
+        "max_tcp_connections",
+        Value::from(config.max_tcp_connections as i64),
+    );
+    line(
+        "cache_max_entries",
+        Value::from(config.cache_max_entries as i64),
+    );
+    line(
+        "cache_max_bytes",
+        Value::from(config.cache_max_bytes as i64),
+    );
+    match &config.metrics_address {
+        Some(metrics_address) => line("metrics_address", Value::from(metrics_address.as_str())),
+        None => text.push_str("# metrics_address = \"127.0.0.1:9153\"\n"),
+    }
+    match &config.pcap_path {
+        Some(pcap_path) => text.push_str(&format!(
+            "pcap_path = {}\n",
+            Value::from(pcap_path.as_str())
+        )),
+        None => text.push_str("# pcap_path = \"/tmp/queensway.pcap\"\n"),
+    }
+
+    for upstream in &config.upstreams {
+        text.push_str(&format!(
+            "\n[[upstreams]]\naddress = {}\ntimeout = {}\nweight = {}\n",
+            Value::from(upstream.address.as_str()),
+            seconds(upstream.timeout),
+            upstream.weight
+        ));
+    }
+
+    match &config.query_log {
+        Some(query_log) => {
+            let format = match query_log.format {
+                LogFormat::Json => "json",
+                LogFormat::Binary => "binary",
+            };
+
+            text.push_str(&format!(
+                "\n[query_log]\npath = {}\nformat = {}\n",
+                Value::from(query_log.path.as_str()),
+                Value::from(format)
+            ));
+            if let Some(max_bytes) = query_log.max_bytes {
+                text.push_str(&format!("max_bytes = {}\n", max_bytes));
+            }
+            if let Some(rotate_interval) = query_log.rotate_interval {
+                text.push_str(&format!("rotate_interval = {}\n", seconds(rotate_interval)));
+            }
+            text.push_str(&format!("keep = {}\n", query_log.keep));
+        }
+        None => text.push_str(
+            "\n# [query_log]\n\
+             # path = \"/var/log/queensway/queries.jsonl\"\n\
+             # format = \"json\"\n\
+             # max_bytes = 104857600\n\
+             # rotate_interval = 86400\n\
+             # keep = 5\n",
+        ),
+    }
+
+    match &config.dnstap {
+        Some(dnstap) => {
+            let (key, path) = match &dnstap.output {
+                DnstapOutput::File(path) => ("file", path),
+                DnstapOutput::UnixSocket(path) => ("socket", path),
+            };
+
+            text.push_str(&format!(
+                "\n[dnstap]\n{} = {}\n",
+                key,
+                Value::from(path.as_str())
+            ));
+            if let Some(identity) = &dnstap.identity {
+                text.push_str(&format!("identity = {}\n", Value::from(identity.as_str())));
+            }
+        }
+        None => text.push_str(
+            "\n# [dnstap]\n\
+             # socket = \"/run/dnstap.sock\"\n",
+        ),
+    }
+
+    text.push_str(
+        "\n# [[rules]]\n\
+         # wildcard = \"*.example.com\"\n\
+         # include_apex = true\n\
+         # records = [\"example.com. 300 IN A 10.0.0.1\"]\n",
+    );
+
+    text
+}
+
+// Whole numbers of seconds are written as integers, for readability
+fn seconds(duration: Duration) -> Value {
+    if duration.subsec_nanos() == 0 {
+        Value::from(duration.as_secs() as i64)
+    } else {
+        Value::from(duration.as_secs_f64())
+    }
+}
+
+fn type_error(key: &str, expected: &str, value: &Value) -> ConfigError {
+    ConfigError::new(
+        key,
+        format!("expected {}, found {}", expected, value.type_str()),
+    )
+}
+
+fn string(key: &str, value: Value) -> Result<String, ConfigError> {
+    match value {
+        Value::String(string) => Ok(string),
+        value => Err(type_error(key, "a string", &value)),
+    }
+}
+
+fn boolean(key: &str, value: Value) -> Result<bool, ConfigError> {
+    match value {
+        Value::Boolean(boolean) => Ok(boolean),
+        value => Err(type_error(key, "a boolean", &value)),
+    }
+}
+
+fn integer(key: &str, value: Value) -> Result<usize, ConfigError> {
+    match value {
+        Value::Integer(integer) => {
+            usize::try_from(integer).map_err(|_| ConfigError::new(key, "must not be negative"))
+        }
+        value => Err(type_error(key, "an integer", &value)),
+    }
+}
+
+fn positive_integer(key: &str, value: Value) -> Result<usize, ConfigError> {
+    match integer(key, value)? {
+        0 => Err(ConfigError::new(key, "must be at least 1")),
+        integer => Ok(integer),
+    }
+}
+
+// Durations are given in seconds, which may be fractional
+fn duration(key: &str, value: Value) -> Result<Duration, ConfigError> {
+    let seconds = match value {
+        Value::Integer(integer) => integer as f64,
+        Value::Float(float) => float,
+        value => return Err(type_error(key, "a number of seconds", &value)),
+    };
+
+    match Duration::try_from_secs_f64(seconds) {
+        Ok(duration) if !duration.is_zero() => Ok(duration),
+        _ => Err(ConfigError::new(
+            key,
+            "must be a positive number of seconds",
+        )),
+    }
+}
+
+fn array(key: &str, value: Value) -> Result<Vec<Value>, ConfigError> {
+    match value {
+        Value::Array(array) => Ok(array),
+        value => Err(type_error(key, "an array", &value)),
+    }
+}
+
+fn table(key: &str, value: Value) -> Result<Table, ConfigError> {
+    match value {
+        Value::Table(table) => Ok(table),
+        value => Err(type_error(key, "a table", &value)),
+    }
+}
+
+fn socket_address(key: &str, value: Value) -> Result<String, ConfigError> {
+    parse_socket_address(key, string(key, value)?)
+}
+
+pub fn parse_socket_address(key: &str, address: String) -> Result<String, ConfigError> {
+    SocketAddr::from_str(&address)
+        .map_err(|_| ConfigError::new(key, format!("invalid socket address `{}`", address)))?;
+
+    Ok(address)
+}
+
+// Upstreams may be given by host name, which is resolved whenever they're queried, so only the port
+// is checked up front
+fn upstream_address(key: &str, value: Value) -> Result<String, ConfigError> {
+    parse_upstream_address(key, string(key, value)?)
+}
+
+pub fn parse_upstream_address(key: &str, address: String) -> Result<String, ConfigError> {
+    match address.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && u16::from_str(port).is_ok() => Ok(address),
+        _ => Err(ConfigError::new(
+            key,
+            format!("expected `host:port`, found `{}`", address),
+        )),
+    }
+}
+
+// Matchers compare names in lowercase, without the trailing dot
+fn name(key: &str, value: Value) -> Result<String, ConfigError> {
+    let name = string(key, value)?;
+
+    match Name::from_str(&name) {
+        Ok(parsed) => Ok(parsed.to_lowercase().to_string()),
+        Err(()) => Err(ConfigError::new(
+            key,
+            format!("invalid domain name `{}`", name),
+        )),
+    }
+}
+
+fn wildcard(key: &str, value: Value) -> Result<String, ConfigError> {
+    let pattern = string(key, value)?;
+
+    match pattern.strip_prefix("*.").map(Name::from_str) {
+        Some(Ok(_)) => Ok(pattern),
+        _ => Err(ConfigError::new(
+            key,
+            format!(
+                "expected a pattern like `*.example.com`, found `{}`",
+                pattern
+            ),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::admission::Overflow;
+    use crate::config::{parse, to_toml};
+    use crate::dnstap::{DnstapConfig, DnstapOutput};
+    use crate::fixture::UpstreamMode;
+    use crate::query_log::{LogFormat, QueryLogConfig};
+    use crate::server::Config;
+    use crate::upstream::Strategy;
+
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse() {
+        let config = parse(
+            r#"
+            bind_address = "0.0.0.0:5353"
+            upstream_strategy = "fastest"
+            upstream_reprobe_interval = 30
+            upstream_mode = "record"
+            upstream_fixture = "upstreams.jsonl"
+            request_timeout = 2.5
+            cache_max_entries = 0
+            overflow = "refuse"
+            max_tcp_connections = 50
+            metrics_address = "127.0.0.1:9153"
+            pcap_path = "queensway.pcap"
+
+            [[upstreams]]
+            address = "1.1.1.1:53"
+            timeout = 1
+            weight = 3
+
+            [[upstreams]]
+            address = "dns.google:53"
+
+            [[rules]]
+            exact = "Example.com."
+            records = ["example.com. 300 IN A 10.0.0.1", "example.com 1h AAAA ::1"]
+
+            [[rules]]
+            wildcard = "*.example.net"
+            include_apex = true
+            records = ["example.net 60 CNAME example.com"]
+
+            [[rules]]
+            set = ["a.com", "b.com"]
+            records = []
+
+            [[rules]]
+            regex = '^ads\d*\.'
+            records = ["ads.com 60 IN MX 10 mail.ads.com"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.bind_address, "0.0.0.0:5353");
+        assert_eq!(
+            config.upstream_strategy,
+            Strategy::Fastest {
+                reprobe_interval: Duration::from_secs(30)
+            }
+        );
+        assert_eq!(
+            config.upstream_mode,
+            UpstreamMode::Record {
+                fixture: "upstreams.jsonl".to_string()
+            }
+        );
+        assert_eq!(config.request_timeout, Duration::from_millis(2500));
+        assert_eq!(config.cache_max_entries, 0);
+        assert_eq!(config.overflow, Overflow::Refuse);
+        assert_eq!(config.max_tcp_connections, 50);
+        assert_eq!(config.metrics_address.as_deref(), Some("127.0.0.1:9153"));
+        assert_eq!(config.pcap_path.as_deref(), Some("queensway.pcap"));
+
+        assert_eq!(config.upstreams.len(), 2);
+        assert_eq!(config.upstreams[0].address, "1.1.1.1:53");
+        assert_eq!(config.upstreams[0].timeout, Duration::from_secs(1));
+        assert_eq!(config.upstreams[0].weight, 3);
+        assert_eq!(config.upstreams[1].weight, 1);
+
+        assert_eq!(config.rules.len(), 4);
+        assert_eq!(config.rules[0].1.len(), 2);
+        assert!(config.rules[0].0.matches("example.com".to_string()));
+        assert!(config.rules[1].0.matches("example.net".to_string()));
+        assert!(config.rules[2].0.matches("b.com".to_string()));
+        assert!(config.rules[3].0.matches("ads12.com".to_string()));
+    }
+
+    #[test]
+    fn test_to_toml() {
+        let mut config = Config {
+            upstream_strategy: Strategy::Fastest {
+                reprobe_interval: Duration::from_millis(1500),
+            },
+            query_log: Some(QueryLogConfig {
+                format: LogFormat::Binary,
+                rotate_interval: Some(Duration::from_secs(3600)),
+                ..QueryLogConfig::new("queries.jsonl".to_string())
+            }),
+            dnstap: Some(DnstapConfig {
+                output: DnstapOutput::UnixSocket("/run/dnstap.sock".to_string()),
+                identity: Some("ns1".to_string()),
+            }),
+            pcap_path: Some("queensway.pcap".to_string()),
+            upstream_mode: UpstreamMode::Replay {
+                fixture: "upstreams.jsonl".to_string(),
+            },
+            ..Default::default()
+        };
+        config.upstreams[1].weight = 5;
+
+        let parsed = parse(&to_toml(&config)).unwrap();
+
+        assert_eq!(parsed.bind_address, config.bind_address);
+        assert_eq!(parsed.upstream_strategy, config.upstream_strategy);
+        assert_eq!(parsed.read_timeout, config.read_timeout);
+        assert_eq!(parsed.overflow, config.overflow);
+        assert_eq!(parsed.cache_max_bytes, config.cache_max_bytes);
+        assert_eq!(parsed.upstreams.len(), 2);
+        assert_eq!(parsed.upstreams[1].address, config.upstreams[1].address);
+        assert_eq!(parsed.upstreams[1].timeout, config.upstreams[1].timeout);
+        assert_eq!(parsed.upstreams[1].weight, 5);
+        assert_eq!(parsed.query_log, config.query_log);
+        assert_eq!(parsed.dnstap, config.dnstap);
+        assert_eq!(parsed.pcap_path, config.pcap_path);
+        assert_eq!(parsed.upstream_mode, config.upstream_mode);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |text: &str| parse(text).err().unwrap().to_string();
+
+        assert_eq!(
+            error("bind_adress = \"0.0.0.0:53\""),
+            "Invalid configuration for `bind_adress`: unknown key"
+        );
+        assert_eq!(
+            error("read_timeout = \"5s\""),
+            "Invalid configuration for `read_timeout`: expected a number of seconds, found string"
+        );
+        assert_eq!(
+            error("drain_timeout = 0"),
+            "Invalid configuration for `drain_timeout`: must be a positive number of seconds"
+        );
+        assert_eq!(
+            error("upstreams = [{ address = \"1.1.1.1:53\", timeout = 0.0 }]"),
+            "Invalid configuration for `upstreams[0].timeout`: must be a positive number of \
+             seconds"
+        );
+        assert_eq!(
+            error("[query_log]\npath = \"queries.jsonl\"\nrotate_interval = -1"),
+            "Invalid configuration for `query_log.rotate_interval`: must be a positive number of \
+             seconds"
+        );
+        assert_eq!(
+            error("upstreams = [{ address = \"1.1.1.1\" }]"),
+            "Invalid configuration for `upstreams[0].address`: expected `host:port`, found `1.1.1.1`"
+        );
+        assert_eq!(
+            error("[[rules]]\nexact = \"a.com\"\nrecords = [\"a.com 60 IN A 10.0.0.300\"]"),
+            "Invalid configuration for `rules[0].records[0]`: invalid IPv4 address `10.0.0.300`"
+        );
+        assert_eq!(
+            error("[[rules]]\nexact = \"a.com\"\nset = [\"b.com\"]\nrecords = []"),
+            "Invalid configuration for `rules[0]`: only one of the keys `exact`, `wildcard`, \
+             `set` or `regex` may be given"
+        );
+        assert_eq!(
+            error("[[rules]]\nexact = \"a.com\"\ninclude_apex = true\nrecords = []"),
+            "Invalid configuration for `rules[0].include_apex`: only applies to `wildcard` rules"
+        );
+        assert_eq!(
+            error("upstream_strategy = \"random\""),
+            "Invalid configuration for `upstream_strategy`: unknown strategy `random`; expected \
+             `ordered`, `round_robin`, `weighted_random` or `fastest`"
+        );
+        assert_eq!(
+            error("upstream_mode = \"replay\""),
+            "Invalid configuration for `upstream_mode`: requires `upstream_fixture` to be given"
+        );
+        assert_eq!(
+            error("upstream_fixture = \"upstreams.jsonl\""),
+            "Invalid configuration for `upstream_fixture`: only applies to the `record` and \
+             `replay` modes"
+        );
+        assert_eq!(
+            error("[query_log]\nmax_bytes = 1024"),
+            "Invalid configuration for `query_log`: missing key `path`"
+        );
+        assert_eq!(
+            error("[dnstap]\nfile = \"a\"\nsocket = \"b\""),
+            "Invalid configuration for `dnstap`: only one of the keys `file` or `socket` may be given"
+        );
+        assert!(error("bind_address = ").starts_with("Invalid configuration: "));
+    }
+}
@@ -0,0 +1,526 @@
+use crate::writer::{Sink, Writer};
+
+use std::fs::File;
+use std::io::{BufWriter, Error as IoError, ErrorKind as IoErrorKind, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::net::UnixStream;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+
+// How long to wait before trying to reconnect to a socket that couldn't be written to
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+// How long a read from or write to the socket may take before the collector is given up on, so that
+// one that stops reading can't hold up the writer indefinitely
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long to wait for the reader to acknowledge the end of the stream
+const FINISH_TIMEOUT: Duration = Duration::from_secs(1);
+
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+
+// Frame Streams control frame types, and the one field they may carry
+const CONTROL_ACCEPT: u32 = 0x01;
+const CONTROL_START: u32 = 0x02;
+const CONTROL_STOP: u32 = 0x03;
+const CONTROL_READY: u32 = 0x04;
+const CONTROL_FINISH: u32 = 0x05;
+const FIELD_CONTENT_TYPE: u32 = 0x01;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DnstapOutput {
+    // Replaced whenever the server starts, as a file holds a single stream
+    File(String),
+    // Connected to with the bidirectional handshake that dnstap collectors expect
+    UnixSocket(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DnstapConfig {
+    pub output: DnstapOutput,
+    // Identifies this server in each message, e.g. by its host name
+    pub identity: Option<String>,
+}
+
+// The values of dnstap's `Message.Type` for the events a proxy sees
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EventType {
+    ClientQuery = 5,
+    ClientResponse = 6,
+    ForwarderQuery = 7,
+    ForwarderResponse = 8,
+}
+
+// A DNS message passing through the server. The query address is that of whichever side sent the
+// query, so it's the client's for client events, and the server's for forwarder events
+#[derive(Debug, PartialEq)]
+pub struct Event {
+    pub type_: EventType,
+    pub transport: &'static str,
+    pub query_address: Option<SocketAddr>,
+    pub response_address: Option<SocketAddr>,
+    pub query_time: SystemTime,
+    pub response_time: Option<SystemTime>,
+    pub message: Vec<u8>,
+}
+
+impl Event {
+    fn is_response(&self) -> bool {
+        matches!(
+            self.type_,
+            EventType::ClientResponse | EventType::ForwarderResponse
+        )
+    }
+
+    // Encodes the event as a `Dnstap` protobuf message, as defined in `dnstap.proto`
+    fn encode(&self, identity: Option<&str>) -> Vec<u8> {
+        let mut message = vec![];
+
+        varint_field(&mut message, 1, self.type_ as u64);
+
+        let family =
+            self.query_address
+                .or(self.response_address)
+                .map(|address| match address.ip() {
+                    IpAddr::V4(_) => 1,
+                    IpAddr::V6(_) => 2,
+                });
+        if let Some(family) = family {
+            varint_field(&mut message, 2, family);
+        }
+
+        let protocol = match self.transport {
+            "tcp" => 2,
+            _ => 1,
+        };
+        varint_field(&mut message, 3, protocol);
+
+        for (address, address_field, port_field) in
+            [(self.query_address, 4, 6), (self.response_address, 5, 7)]
+        {
+            if let Some(address) = address {
+                let ip = match address.ip() {
+                    IpAddr::V4(ip) => ip.octets().to_vec(),
+                    IpAddr::V6(ip) => ip.octets().to_vec(),
+                };
+
+                bytes_field(&mut message, address_field, &ip);
+                varint_field(&mut message, port_field, address.port() as u64);
+            }
+        }
+
+        time_fields(&mut message, 8, self.query_time);
+
+        if self.is_response() {
+            if let Some(response_time) = self.response_time {
+                time_fields(&mut message, 12, response_time);
+            }
+            bytes_field(&mut message, 14, &self.message);
+        } else {
+            bytes_field(&mut message, 10, &self.message);
+        }
+
+        let mut dnstap = vec![];
+
+        if let Some(identity) = identity {
+            bytes_field(&mut dnstap, 1, identity.as_bytes());
+        }
+        let version = concat!("queensway ", env!("CARGO_PKG_VERSION"));
+        bytes_field(&mut dnstap, 2, version.as_bytes());
+        bytes_field(&mut dnstap, 14, &message);
+        // The only type of `Dnstap` message is `MESSAGE`
+        varint_field(&mut dnstap, 15, 1);
+
+        dnstap
+    }
+}
+
+fn varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+// Each field starts with its number and wire type: 0 for varints, 2 for length-delimited values,
+// or 5 for 32-bit values
+fn varint_field(bytes: &mut Vec<u8>, field: u64, value: u64) {
+    varint(bytes, field << 3);
+    varint(bytes, value);
+}
+
+fn bytes_field(bytes: &mut Vec<u8>, field: u64, value: &[u8]) {
+    varint(bytes, (field << 3) | 2);
+    varint(bytes, value.len() as u64);
+    bytes.extend(value);
+}
+
+// Times are given in seconds, followed in the next field by nanoseconds
+fn time_fields(bytes: &mut Vec<u8>, field: u64, time: SystemTime) {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+
+    varint_field(bytes, field, since_epoch.as_secs());
+    varint(bytes, ((field + 1) << 3) | 5);
+    bytes.extend(since_epoch.subsec_nanos().to_le_bytes());
+}
+
+// Writes events as Frame Streams data frames from a dedicated thread. Events are discarded while
+// the socket is down
+pub type Dnstap = Writer<Event>;
+
+impl Dnstap {
+    // Files are opened straight away, so that mistakes show up on startup; sockets are connected to
+    // by the writer, which keeps trying until the collector is listening
+    pub fn open(config: DnstapConfig) -> Result<Self, IoError> {
+        let output = match &config.output {
+            DnstapOutput::File(path) => Some(Output::file(path)?),
+            DnstapOutput::UnixSocket(_) => None,
+        };
+
+        let stream = Stream {
+            config,
+            output,
+            next_connection: Instant::now(),
+        };
+
+        Writer::spawn("dnstap", stream)
+    }
+}
+
+enum Output {
+    File(BufWriter<File>),
+    Socket(BufWriter<UnixStream>),
+}
+
+impl Output {
+    fn file(path: &str) -> Result<Self, IoError> {
+        let mut output = Output::File(BufWriter::new(File::create(path)?));
+        output.write_control(CONTROL_START, true)?;
+        Ok(output)
+    }
+
+    fn socket(path: &str) -> Result<Self, IoError> {
+        let stream = UnixStream::connect(path)?;
+        stream.set_read_timeout(Some(SOCKET_TIMEOUT))?;
+        stream.set_write_timeout(Some(SOCKET_TIMEOUT))?;
+
+        let mut output = Output::Socket(BufWriter::new(stream));
+
+        output.write_control(CONTROL_READY, true)?;
+        output.flush()?;
+        output.read_control(CONTROL_ACCEPT)?;
+        output.write_control(CONTROL_START, true)?;
+        output.flush()?;
+
+        Ok(output)
+    }
+
+    fn writer(&mut self) -> &mut dyn Write {
+        match self {
+            Output::File(file) => file,
+            Output::Socket(socket) => socket,
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.writer().flush()
+    }
+
+    fn write_frame(&mut self, frame: &[u8]) -> Result<(), IoError> {
+        let writer = self.writer();
+        writer.write_all(&(frame.len() as u32).to_be_bytes())?;
+        writer.write_all(frame)
+    }
+
+    // Control frames are escaped by a zero length, which no data frame can have
+    fn write_control(&mut self, type_: u32, content_type: bool) -> Result<(), IoError> {
+        let mut frame = type_.to_be_bytes().to_vec();
+
+        if content_type {
+            frame.extend(FIELD_CONTENT_TYPE.to_be_bytes());
+            frame.extend((CONTENT_TYPE.len() as u32).to_be_bytes());
+            frame.extend(CONTENT_TYPE);
+        }
+
+        self.writer().write_all(&0u32.to_be_bytes())?;
+        self.write_frame(&frame)
+    }
+
+    // Reads a control frame from the collector, which must be of the given type; any content types
+    // it lists are taken on trust
+    fn read_control(&mut self, expected_type: u32) -> Result<(), IoError> {
+        let Output::Socket(socket) = self else {
+            return Ok(());
+        };
+        let mut stream = socket.get_ref();
+
+        let mut header = [0; 12];
+        stream.read_exact(&mut header[0..8])?;
+
+        let escape = u32::from_be_bytes(header[0..4].try_into().unwrap());
+        let len = u32::from_be_bytes(header[4..8].try_into().unwrap()) as usize;
+
+        if escape != 0 || !(4..=512).contains(&len) {
+            return Err(IoError::new(
+                IoErrorKind::InvalidData,
+                "expected a Frame Streams control frame",
+            ));
+        }
+
+        let mut frame = vec![0; len];
+        stream.read_exact(&mut frame)?;
+
+        let type_ = u32::from_be_bytes(frame[0..4].try_into().unwrap());
+        if type_ != expected_type {
+            return Err(IoError::new(
+                IoErrorKind::InvalidData,
+                format!(
+                    "expected Frame Streams control frame of type {}, found {}",
+                    expected_type, type_
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    // Ends the stream, waiting briefly for a collector to acknowledge it
+    fn finish(&mut self) -> Result<(), IoError> {
+        self.write_control(CONTROL_STOP, false)?;
+        self.flush()?;
+
+        if let Output::Socket(socket) = self {
+            socket.get_ref().set_read_timeout(Some(FINISH_TIMEOUT))?;
+            self.read_control(CONTROL_FINISH)?;
+        }
+
+        Ok(())
+    }
+}
+
+struct Stream {
+    config: DnstapConfig,
+    output: Option<Output>,
+    next_connection: Instant,
+}
+
+impl Sink for Stream {
+    type Item = Event;
+
+    fn write(&mut self, event: Event) {
+        let frame = event.encode(self.config.identity.as_deref());
+
+        let result = match self.output() {
+            Some(output) => output.write_frame(&frame),
+            None => return,
+        };
+
+        if let Err(error) = result {
+            self.fail(error);
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Some(output) = &mut self.output {
+            if let Err(error) = output.flush() {
+                self.fail(error);
+            }
+        }
+    }
+
+    fn finish(&mut self) {
+        if let Some(output) = &mut self.output {
+            if let Err(error) = output.finish() {
+                warn!("Error ending dnstap stream: {}", error);
+            }
+        }
+
+        self.output = None;
+    }
+}
+
+impl Stream {
+    // Connects to the socket if need be, unless the last attempt was too recent
+    fn output(&mut self) -> Option<&mut Output> {
+        if self.output.is_none() && Instant::now() >= self.next_connection {
+            if let DnstapOutput::UnixSocket(path) = &self.config.output {
+                match Output::socket(path) {
+                    Ok(output) => {
+                        info!("Connected to dnstap socket {}", path);
+                        self.output = Some(output);
+                    }
+                    Err(error) => {
+                        warn!("Error connecting to dnstap socket {}: {}", path, error);
+                        self.next_connection = Instant::now() + RECONNECT_INTERVAL;
+                    }
+                }
+            }
+        }
+
+        self.output.as_mut()
+    }
+
+    // A socket that can't be written to is dropped, to be reconnected to later; a file is kept, in
+    // case the error was temporary
+    fn fail(&mut self, error: IoError) {
+        match &self.config.output {
+            DnstapOutput::File(path) => warn!("Error writing to dnstap file {}: {}", path, error),
+            DnstapOutput::UnixSocket(path) => {
+                warn!("Error writing to dnstap socket {}: {}", path, error);
+                self.output = None;
+                self.next_connection = Instant::now() + RECONNECT_INTERVAL;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::dnstap::{Dnstap, DnstapConfig, DnstapOutput, Event, EventType};
+    use crate::test_util::temp_path;
+
+    use std::fs::{read, remove_file};
+    use std::io::{Read, Write};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::thread;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    // Reads a frame, giving the type of a control frame, or None for a data frame
+    fn read_frame(stream: &mut UnixStream) -> (Option<u32>, Vec<u8>) {
+        let mut len = [0; 4];
+        stream.read_exact(&mut len).unwrap();
+
+        let control = u32::from_be_bytes(len) == 0;
+        if control {
+            stream.read_exact(&mut len).unwrap();
+        }
+
+        let mut frame = vec![0; u32::from_be_bytes(len) as usize];
+        stream.read_exact(&mut frame).unwrap();
+
+        if control {
+            let type_ = u32::from_be_bytes(frame[0..4].try_into().unwrap());
+            (Some(type_), frame[4..].to_vec())
+        } else {
+            (None, frame)
+        }
+    }
+
+    fn content_type() -> Vec<u8> {
+        let mut field = vec![0, 0, 0, 1, 0, 0, 0, 22];
+        field.extend(b"protobuf:dnstap.Dnstap");
+        field
+    }
+
+    #[test]
+    fn test_dnstap_file() {
+        let path = temp_path("file.dnstap");
+
+        let config = DnstapConfig {
+            output: DnstapOutput::File(path.to_str().unwrap().to_string()),
+            identity: Some("ns1".to_string()),
+        };
+
+        let dnstap = Dnstap::open(config).unwrap();
+        dnstap.write(Event {
+            type_: EventType::ClientQuery,
+            transport: "udp",
+            query_address: Some("127.0.0.1:5353".parse().unwrap()),
+            response_address: None,
+            query_time: UNIX_EPOCH + Duration::new(1, 2),
+            response_time: None,
+            message: vec![0xab],
+        });
+        assert!(dnstap.close(Duration::from_secs(5)));
+
+        let bytes = read(&path).unwrap();
+        remove_file(&path).unwrap();
+
+        let mut start = vec![0, 0, 0, 0, 0, 0, 0, 34, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 22];
+        start.extend(b"protobuf:dnstap.Dnstap");
+        assert_eq!(&bytes[0..start.len()], &start[..]);
+
+        let message = [
+            0x08, 5, // type: CLIENT_QUERY
+            0x10, 1, // socket_family: INET
+            0x18, 1, // socket_protocol: UDP
+            0x22, 4, 127, 0, 0, 1, // query_address
+            0x30, 0xe9, 0x29, // query_port: 5353
+            0x40, 1, // query_time_sec
+            0x4d, 2, 0, 0, 0, // query_time_nsec
+            0x52, 1, 0xab, // query_message
+        ];
+
+        let mut frame = vec![0x0a, 3];
+        frame.extend(b"ns1");
+        let version = concat!("queensway ", env!("CARGO_PKG_VERSION"));
+        frame.extend([0x12, version.len() as u8]);
+        frame.extend(version.as_bytes());
+        frame.extend([0x72, message.len() as u8]);
+        frame.extend(message);
+        frame.extend([0x78, 1]);
+
+        let data = &bytes[start.len()..];
+        assert_eq!(&data[0..4], &(frame.len() as u32).to_be_bytes());
+        assert_eq!(&data[4..4 + frame.len()], &frame[..]);
+
+        let stop = [0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 3];
+        assert_eq!(&data[4 + frame.len()..], &stop);
+    }
+
+    #[test]
+    fn test_dnstap_socket() {
+        let path = temp_path("dnstap.sock");
+        let _ = remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        // Plays the collector's side of the bidirectional handshake
+        let collector = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+
+            assert_eq!(read_frame(&mut stream), (Some(4), content_type()));
+
+            let mut accept = vec![0, 0, 0, 0, 0, 0, 0, 34, 0, 0, 0, 1];
+            accept.extend(content_type());
+            stream.write_all(&accept).unwrap();
+
+            assert_eq!(read_frame(&mut stream), (Some(2), content_type()));
+
+            let (type_, frame) = read_frame(&mut stream);
+            assert_eq!(type_, None);
+
+            assert_eq!(read_frame(&mut stream), (Some(3), vec![]));
+
+            stream
+                .write_all(&[0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 5])
+                .unwrap();
+
+            frame
+        });
+
+        let config = DnstapConfig {
+            output: DnstapOutput::UnixSocket(path.to_str().unwrap().to_string()),
+            identity: None,
+        };
+
+        // The socket is connected to when the first event is written
+        let dnstap = Dnstap::open(config).unwrap();
+        dnstap.write(Event {
+            type_: EventType::ClientQuery,
+            transport: "udp",
+            query_address: Some("127.0.0.1:5353".parse().unwrap()),
+            response_address: None,
+            query_time: UNIX_EPOCH,
+            response_time: None,
+            message: vec![0xab],
+        });
+        assert!(dnstap.close(Duration::from_secs(5)));
+
+        let frame = collector.join().unwrap();
+        remove_file(&path).unwrap();
+
+        assert_eq!(&frame[frame.len() - 2..], &[0x78, 1]);
+    }
+}
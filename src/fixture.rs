@@ -0,0 +1,213 @@
+use crate::protocol::{Message, QuestionKey};
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{read_to_string, File};
+use std::io::{Error as IoError, Write};
+use std::sync::Mutex;
+
+use serde_json::{json, Value};
+
+// Where queries that aren't answered locally or from the cache are answered from
+#[derive(Clone, Debug, PartialEq)]
+pub enum UpstreamMode {
+    // Send them to the upstreams
+    Network,
+    // Send them to the upstreams, and record each exchange to the fixture
+    Record { fixture: String },
+    // Answer them from the exchanges recorded in the fixture, without any network access
+    Replay { fixture: String },
+}
+
+// Records exchanges with upstreams as JSON Lines, one per exchange, giving the upstream and both
+// messages in hex. The question is given too, for the benefit of anyone reading the fixture.
+// Recording is meant for building fixtures, not for serving real traffic, so each exchange is
+// simply written as soon as it completes
+pub struct Recorder {
+    file: Mutex<File>,
+}
+
+impl Recorder {
+    // The fixture is replaced, so that it holds exactly the exchanges of one run
+    pub fn create(path: &str) -> Result<Self, IoError> {
+        Ok(Self {
+            file: Mutex::new(File::create(path)?),
+        })
+    }
+
+    pub fn record(
+        &self,
+        upstream_address: &str,
+        query_bytes: &[u8],
+        reply_bytes: &[u8],
+    ) -> Result<(), IoError> {
+        let question = Message::parse(query_bytes)
+            .ok()
+            .and_then(|query| query.question().cloned());
+
+        let exchange = json!({
+            "qname": question.as_ref().map(|question| question.name().to_string()),
+            "qtype": question.as_ref().map(|question| question.type_().name()),
+            "upstream": upstream_address,
+            "query": hex(query_bytes),
+            "reply": hex(reply_bytes),
+        });
+
+        // Each line goes out in a single write, so that a fixture is never left with half of one
+        let mut line = serde_json::to_vec(&exchange)?;
+        line.push(b'\n');
+
+        self.file.lock().unwrap().write_all(&line)
+    }
+}
+
+// Answers queries from a fixture written by `Recorder`, matching them to recorded exchanges on
+// their question alone. Where a question was asked more than once, its replies are given in the
+// order they were recorded, with the last repeated once they run out
+pub struct Replayer {
+    replies: Mutex<HashMap<QuestionKey, Replies>>,
+}
+
+struct Replies {
+    // Each reply, along with the upstream that gave it
+    replies: Vec<(Message, String)>,
+    next: usize,
+}
+
+impl Replayer {
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let text = read_to_string(path)?;
+
+        let mut replies = HashMap::new();
+
+        for (index, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let (key, reply, upstream_address) = exchange(line)
+                .map_err(|error| format!("Invalid exchange on line {}: {}", index + 1, error))?;
+
+            replies
+                .entry(key)
+                .or_insert_with(|| Replies {
+                    replies: vec![],
+                    next: 0,
+                })
+                .replies
+                .push((reply, upstream_address));
+        }
+
+        Ok(Self {
+            replies: Mutex::new(replies),
+        })
+    }
+
+    // The number of distinct questions recorded
+    pub fn question_count(&self) -> usize {
+        self.replies.lock().unwrap().len()
+    }
+
+    // Finds the next recorded reply to the query's question, adapted to carry the query's ID, along
+    // with the upstream that gave it
+    pub fn reply(&self, query: &Message) -> Option<(Message, String)> {
+        let mut replies = self.replies.lock().unwrap();
+        let replies = replies.get_mut(&query.question_key()?)?;
+
+        let (reply, upstream_address) =
+            &replies.replies[replies.next.min(replies.replies.len() - 1)];
+        replies.next += 1;
+
+        Some((reply.reuse_for(query, 0), upstream_address.clone()))
+    }
+}
+
+fn exchange(line: &str) -> Result<(QuestionKey, Message, String), Box<dyn Error + Send + Sync>> {
+    let exchange = serde_json::from_str::<Value>(line)?;
+
+    let field = |name: &str| {
+        exchange[name]
+            .as_str()
+            .ok_or_else(|| format!("missing field `{}`", name))
+    };
+
+    let bytes = |name: &str| -> Result<Vec<u8>, String> {
+        unhex(field(name)?).ok_or_else(|| format!("field `{}` isn't valid hex", name))
+    };
+
+    let query = Message::parse(&bytes("query")?)?;
+    let reply = Message::parse(&bytes("reply")?)?;
+
+    let key = query
+        .question_key()
+        .ok_or("query isn't a standard query with a question")?;
+
+    Ok((key, reply, field("upstream")?.to_string()))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..text.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(text.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::fixture::{Recorder, Replayer};
+    use crate::protocol::Message;
+    use crate::test_util::{query, reply_to, temp_path, A};
+
+    use std::fs::{remove_file, write};
+
+    #[test]
+    fn test_record_and_replay() {
+        let path = temp_path("record.fixture").to_str().unwrap().to_string();
+
+        let recorder = Recorder::create(&path).unwrap();
+        let a = query(1, "a.com", A);
+        let b = query(2, "b.com", A);
+        recorder.record("8.8.8.8:53", &a, &reply_to(&a, 3)).unwrap();
+        recorder.record("8.8.4.4:53", &b, &reply_to(&b, 0)).unwrap();
+        recorder.record("8.8.8.8:53", &a, &reply_to(&a, 0)).unwrap();
+
+        let replayer = Replayer::load(&path).unwrap();
+        assert_eq!(replayer.question_count(), 2);
+
+        // Queries are matched regardless of their ID or the case of their name
+        let message = Message::parse(&query(7, "A.com", A)).unwrap();
+
+        let (reply, upstream_address) = replayer.reply(&message).unwrap();
+        assert!(reply.is_reply_to(&message));
+        assert_eq!(reply.flags().response_code().name(), "NXDomain");
+        assert_eq!(upstream_address, "8.8.8.8:53");
+
+        // Later replies to the same question follow in order, and the last is repeated
+        for _ in 0..2 {
+            let (reply, _) = replayer.reply(&message).unwrap();
+            assert_eq!(reply.flags().response_code().name(), "NoError");
+        }
+
+        let (_, upstream_address) = replayer.reply(&Message::parse(&b).unwrap()).unwrap();
+        assert_eq!(upstream_address, "8.8.4.4:53");
+
+        let unrecorded = Message::parse(&query(3, "c.com", A)).unwrap();
+        assert!(replayer.reply(&unrecorded).is_none());
+
+        write(&path, "{\"upstream\": \"8.8.8.8:53\", \"query\": \"0g\"}\n").unwrap();
+        assert_eq!(
+            Replayer::load(&path).err().unwrap().to_string(),
+            "Invalid exchange on line 1: field `query` isn't valid hex"
+        );
+
+        remove_file(&path).unwrap();
+    }
+}
@@ -1,14 +1,304 @@
+mod admission;
+mod cache;
+mod coalesce;
+mod config;
+mod dnstap;
+mod fixture;
 mod matcher;
+mod metrics;
+mod pcap;
+mod pool;
 mod protocol;
+mod query_log;
 mod server;
+#[cfg(test)]
+mod test_util;
+mod upstream;
+mod writer;
 
-use crate::server::{bind_and_serve, Config};
+use crate::query_log::Reader as QueryLogReader;
+use crate::server::{Config, Server};
+use crate::upstream::Upstream;
 
-use env_logger::{Builder as LoggerBuilder,Env};
+use std::error::Error;
+use std::fs::File;
+use std::io::{stdout, BufReader, ErrorKind as IoErrorKind, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::process::exit;
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::{Args, Parser, Subcommand};
+use env_logger::{Builder as LoggerBuilder, Env};
+use log::{info, warn};
+use tokio::signal::ctrl_c;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::spawn;
+
+// Settings given on the command line take precedence over those in the configuration file
+#[derive(Parser)]
+#[clap(version, about = "A caching, rule-based DNS proxy")]
+struct Options {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    #[clap(
+        long,
+        value_name = "PATH",
+        help = "Read the configuration from a TOML file, instead of using the defaults"
+    )]
+    config: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "ADDRESS",
+        help = "Serve DNS queries on this address and port"
+    )]
+    bind: Option<String>,
+
+    #[clap(
+        long = "upstream",
+        value_name = "ADDRESS",
+        help = "Proxy DNS queries to this address and port; may be given more than once"
+    )]
+    upstreams: Vec<String>,
+
+    #[clap(
+        long,
+        value_name = "LEVEL",
+        possible_values = ["off", "error", "warn", "info", "debug", "trace"],
+        help = "Log messages of this level and above, overriding RUST_LOG [default: warn]"
+    )]
+    log_level: Option<String>,
+
+    #[clap(long, help = "Validate the configuration, then exit")]
+    check_config: bool,
+
+    #[clap(long, help = "Print the default configuration as TOML, then exit")]
+    print_default_config: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[clap(about = "Print the entries of a binary query log, optionally filtered")]
+    ReadLog(ReadLogOptions),
+}
+
+// Entries are printed only if they match every filter given
+#[derive(Args)]
+struct ReadLogOptions {
+    #[clap(value_name = "PATH", help = "The binary query log to read")]
+    path: String,
+
+    #[clap(
+        long,
+        value_name = "NAME",
+        help = "Only print queries for this domain name or its subdomains"
+    )]
+    name: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "ADDRESS",
+        help = "Only print queries from this IP address, or address and port"
+    )]
+    client: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "RCODE",
+        help = "Only print replies with this response code, e.g. NXDomain"
+    )]
+    rcode: Option<String>,
+}
+
+// Upstreams given on the command line get the same timeout as the defaults
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(2);
 
 #[tokio::main]
 async fn main() {
-    LoggerBuilder::from_env(Env::default().default_filter_or("warn")).init();
-    let config = Config::default();
-    bind_and_serve(config).await.unwrap(); // FIXME
+    let options = Options::parse();
+
+    match &options.log_level {
+        Some(level) => LoggerBuilder::new().parse_filters(level).init(),
+        None => LoggerBuilder::from_env(Env::default().default_filter_or("warn")).init(),
+    }
+
+    if let Some(Command::ReadLog(read_log_options)) = &options.command {
+        if let Err(error) = read_log(read_log_options) {
+            eprintln!("{}", error);
+            exit(1);
+        }
+        return;
+    }
+
+    if options.print_default_config {
+        print!("{}", config::to_toml(&Config::default()));
+        return;
+    }
+
+    let config = match load_config(&options) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("{}", error);
+            exit(1);
+        }
+    };
+
+    if options.check_config {
+        println!("Configuration is valid");
+        return;
+    }
+
+    let server = match Server::bind(config).await {
+        Ok(server) => server,
+        Err(error) => {
+            eprintln!("{}", error);
+            exit(1);
+        }
+    };
+
+    spawn(reload_on_hangup(server.clone(), options));
+    spawn(shut_down_on_termination(server.clone()));
+
+    if let Err(error) = server.serve().await {
+        eprintln!("{}", error);
+        exit(1);
+    }
+
+    log::logger().flush();
+}
+
+// Shuts the server down gracefully on SIGTERM or SIGINT
+async fn shut_down_on_termination(server: Arc<Server>) {
+    let mut terminations = match signal(SignalKind::terminate()) {
+        Ok(terminations) => terminations,
+        Err(error) => {
+            warn!("Unable to listen for SIGTERM: {}", error);
+            return;
+        }
+    };
+
+    tokio::select! {
+        _ = terminations.recv() => info!("Received SIGTERM"),
+        _ = ctrl_c() => info!("Received SIGINT"),
+    }
+
+    server.shutdown();
+}
+
+// Re-reads the configuration whenever the process receives SIGHUP, keeping the current one if the
+// new one is invalid
+async fn reload_on_hangup(server: Arc<Server>, options: Options) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(error) => {
+            warn!("Unable to listen for SIGHUP: {}", error);
+            return;
+        }
+    };
+
+    while hangups.recv().await.is_some() {
+        info!("Received SIGHUP, reloading configuration");
+
+        match load_config(&options) {
+            Ok(config) => server.reload(config),
+            Err(error) => warn!("Keeping the current configuration: {}", error),
+        }
+    }
+}
+
+fn load_config(options: &Options) -> Result<Config, Box<dyn Error + Send + Sync>> {
+    let mut config = match &options.config {
+        Some(path) => config::load(path)?,
+        None => Config::default(),
+    };
+
+    if let Some(bind) = &options.bind {
+        config.bind_address = config::parse_socket_address("--bind", bind.clone())?;
+    }
+
+    if !options.upstreams.is_empty() {
+        config.upstreams = options
+            .upstreams
+            .iter()
+            .map(|address| {
+                let address = config::parse_upstream_address("--upstream", address.clone())?;
+                Ok(Upstream::new(address, UPSTREAM_TIMEOUT))
+            })
+            .collect::<Result<_, config::ConfigError>>()?;
+    }
+
+    Ok(config)
+}
+
+fn read_log(options: &ReadLogOptions) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let read_error = |error| format!("Error reading query log {}: {}", options.path, error);
+
+    let name = options
+        .name
+        .as_ref()
+        .map(|name| name.trim_end_matches('.').to_ascii_lowercase());
+
+    let client = match &options.client {
+        Some(client) => Some(match client.parse::<SocketAddr>() {
+            Ok(address) => (address.ip(), Some(address.port())),
+            Err(_) => match client.parse::<IpAddr>() {
+                Ok(ip) => (ip, None),
+                Err(_) => return Err(format!("Invalid client address `{}`", client).into()),
+            },
+        }),
+        None => None,
+    };
+
+    let file = File::open(&options.path).map_err(read_error)?;
+    let reader = QueryLogReader::new(BufReader::new(file)).map_err(read_error)?;
+
+    let stdout = stdout();
+    let mut stdout = stdout.lock();
+
+    for entry in reader {
+        let entry = entry.map_err(read_error)?;
+
+        if let Some(name) = &name {
+            let qname = entry
+                .query()
+                .and_then(|query| query.question().map(|question| question.name().to_string()))
+                .map(|qname| qname.trim_end_matches('.').to_ascii_lowercase());
+
+            match qname {
+                Some(qname) if qname == *name || qname.ends_with(&format!(".{}", name)) => (),
+                _ => continue,
+            }
+        }
+
+        if let Some((ip, port)) = client {
+            if entry.client.ip() != ip || port.is_some_and(|port| entry.client.port() != port) {
+                continue;
+            }
+        }
+
+        if let Some(rcode) = &options.rcode {
+            let matches = entry.reply().is_some_and(|reply| {
+                reply
+                    .flags()
+                    .response_code()
+                    .name()
+                    .eq_ignore_ascii_case(rcode)
+            });
+
+            if !matches {
+                continue;
+            }
+        }
+
+        // The reader may well be piped into something like `head`, which stops reading early
+        match writeln!(stdout, "{}", entry) {
+            Ok(()) => (),
+            Err(error) if error.kind() == IoErrorKind::BrokenPipe => return Ok(()),
+            Err(error) => return Err(error.into()),
+        }
+    }
+
+    Ok(())
 }
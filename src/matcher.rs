@@ -1,9 +1,13 @@
 use regex::Regex;
 use std::collections::HashSet;
 
+// Queries are matched by their lowercased name, so the names given to exact and set matchers should
+// be lowercase too
 pub enum Matcher {
     Exact { name: String },
-    Wildcard { pattern: String },
+    // Patterns take the form `*.example.com`, where the `*` stands in for one or more whole labels;
+    // `example.com` itself is matched only if `include_apex` is set
+    Wildcard { pattern: String, include_apex: bool },
     Set { names: HashSet<String> },
     Regex { regex: Regex },
 }
@@ -12,9 +16,64 @@ impl Matcher {
     pub fn matches(&self, query: String) -> bool {
         match self {
             Self::Exact { name } => name == &query,
-            Self::Wildcard { pattern } => unimplemented!(), // FIXME
+            Self::Wildcard {
+                pattern,
+                include_apex,
+            } => {
+                let query = query.strip_suffix('.').unwrap_or(&query).as_bytes();
+
+                let suffix = pattern.strip_suffix('.').unwrap_or(pattern);
+                let suffix = suffix.strip_prefix("*.").unwrap_or(suffix).as_bytes();
+
+                if query.len() == suffix.len() {
+                    *include_apex && query.eq_ignore_ascii_case(suffix)
+                } else if query.len() > suffix.len() + 1 {
+                    let (head, tail) = query.split_at(query.len() - suffix.len());
+                    head.ends_with(b".") && tail.eq_ignore_ascii_case(suffix)
+                } else {
+                    false
+                }
+            }
             Self::Set { names } => names.contains(&query),
             Self::Regex { regex } => regex.is_match(&query),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::matcher::Matcher;
+
+    fn wildcard(pattern: &str, include_apex: bool) -> Matcher {
+        Matcher::Wildcard {
+            pattern: pattern.to_string(),
+            include_apex,
+        }
+    }
+
+    #[test]
+    fn test_wildcard_matches() {
+        let matcher = wildcard("*.example.com", false);
+
+        assert!(matcher.matches("www.example.com".to_string()));
+        assert!(matcher.matches("a.b.c.example.com".to_string()));
+        assert!(matcher.matches("WWW.Example.COM".to_string()));
+        assert!(matcher.matches("www.example.com.".to_string()));
+
+        assert!(!matcher.matches("example.com".to_string()));
+        assert!(!matcher.matches("wwwexample.com".to_string()));
+        assert!(!matcher.matches(".example.com".to_string()));
+        assert!(!matcher.matches("example.com.evil.net".to_string()));
+        assert!(!matcher.matches("com".to_string()));
+    }
+
+    #[test]
+    fn test_wildcard_matches_apex() {
+        let matcher = wildcard("*.example.com.", true);
+
+        assert!(matcher.matches("example.com".to_string()));
+        assert!(matcher.matches("EXAMPLE.com.".to_string()));
+        assert!(matcher.matches("www.example.com".to_string()));
+        assert!(!matcher.matches("notexample.com".to_string()));
+    }
+}
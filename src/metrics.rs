@@ -0,0 +1,305 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Upper bounds of the latency histograms' buckets, in seconds
+const LATENCY_BUCKETS: [f64; 13] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+// Everything the server counts and times, rendered in the Prometheus text exposition format
+pub struct Metrics {
+    pub queries: Counter,
+    pub request_duration: Histogram,
+    pub requests_shed: Counter,
+    pub rule_hits: Counter,
+    pub cache_hits: Counter,
+    pub cache_misses: Counter,
+    pub upstream_requests: Counter,
+    pub upstream_failures: Counter,
+    pub upstream_timeouts: Counter,
+    pub upstream_duration: Histogram,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            queries: Counter::new(
+                "queensway_queries_total",
+                "DNS queries answered, by query type and response code",
+                &["qtype", "rcode"],
+            ),
+            request_duration: Histogram::new(
+                "queensway_request_duration_seconds",
+                "Time taken to answer DNS queries, by transport",
+                &["transport"],
+            ),
+            requests_shed: Counter::new(
+                "queensway_requests_shed_total",
+                "DNS queries shed while at max_concurrent_requests, by how they were shed",
+                &["action"],
+            ),
+            rule_hits: Counter::new(
+                "queensway_rule_hits_total",
+                "DNS queries answered by each rule, by its index in the configuration",
+                &["rule"],
+            ),
+            cache_hits: Counter::new(
+                "queensway_cache_hits_total",
+                "DNS queries answered from the cache",
+                &[],
+            ),
+            cache_misses: Counter::new(
+                "queensway_cache_misses_total",
+                "DNS queries not found in the cache",
+                &[],
+            ),
+            upstream_requests: Counter::new(
+                "queensway_upstream_requests_total",
+                "DNS queries sent to each upstream",
+                &["upstream"],
+            ),
+            upstream_failures: Counter::new(
+                "queensway_upstream_failures_total",
+                "DNS queries to each upstream that failed, or were answered with SERVFAIL or \
+                 REFUSED",
+                &["upstream"],
+            ),
+            upstream_timeouts: Counter::new(
+                "queensway_upstream_timeouts_total",
+                "DNS queries to each upstream that timed out",
+                &["upstream"],
+            ),
+            upstream_duration: Histogram::new(
+                "queensway_upstream_duration_seconds",
+                "Time taken by each upstream to reply",
+                &["upstream"],
+            ),
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+
+        for counter in [
+            &self.queries,
+            &self.requests_shed,
+            &self.rule_hits,
+            &self.cache_hits,
+            &self.cache_misses,
+            &self.upstream_requests,
+            &self.upstream_failures,
+            &self.upstream_timeouts,
+        ] {
+            counter.write_to(&mut text);
+        }
+
+        for histogram in [&self.request_duration, &self.upstream_duration] {
+            histogram.write_to(&mut text);
+        }
+
+        text
+    }
+}
+
+// A counter, partitioned by the values of its labels
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, u64>>,
+}
+
+impl Counter {
+    fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Self {
+        Self {
+            name,
+            help,
+            labels,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn inc(&self, labels: &[&str]) {
+        debug_assert_eq!(labels.len(), self.labels.len());
+        *self.values.lock().unwrap().entry(key(labels)).or_default() += 1;
+    }
+
+    // For counts that are kept elsewhere, and copied in just before rendering
+    pub fn set(&self, labels: &[&str], value: u64) {
+        debug_assert_eq!(labels.len(), self.labels.len());
+        self.values.lock().unwrap().insert(key(labels), value);
+    }
+
+    fn write_to(&self, text: &mut String) {
+        write_header(text, self.name, self.help, "counter");
+
+        let values = self.values.lock().unwrap();
+
+        // Unlabelled counters are always present, even before they've been incremented
+        if self.labels.is_empty() && values.is_empty() {
+            let _ = writeln!(text, "{} 0", self.name);
+        }
+
+        for (values, count) in values.iter() {
+            let _ = writeln!(
+                text,
+                "{}{} {}",
+                self.name,
+                label_set(self.labels, values, None),
+                count
+            );
+        }
+    }
+}
+
+// A histogram of durations, partitioned by the values of its labels
+pub struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, Observations>>,
+}
+
+#[derive(Default)]
+struct Observations {
+    // Per bucket, rather than cumulative; the last is for observations exceeding every bound
+    counts: [u64; LATENCY_BUCKETS.len() + 1],
+    sum: f64,
+}
+
+impl Histogram {
+    fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Self {
+        Self {
+            name,
+            help,
+            labels,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn observe(&self, labels: &[&str], duration: Duration) {
+        debug_assert_eq!(labels.len(), self.labels.len());
+
+        let seconds = duration.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+
+        let mut values = self.values.lock().unwrap();
+        let observations = values.entry(key(labels)).or_default();
+        observations.counts[bucket] += 1;
+        observations.sum += seconds;
+    }
+
+    fn write_to(&self, text: &mut String) {
+        write_header(text, self.name, self.help, "histogram");
+
+        for (values, observations) in self.values.lock().unwrap().iter() {
+            let mut cumulative = 0;
+
+            for (index, count) in observations.counts.iter().enumerate() {
+                cumulative += count;
+
+                let bound = match LATENCY_BUCKETS.get(index) {
+                    Some(bound) => bound.to_string(),
+                    None => "+Inf".to_string(),
+                };
+
+                let _ = writeln!(
+                    text,
+                    "{}_bucket{} {}",
+                    self.name,
+                    label_set(self.labels, values, Some(&bound)),
+                    cumulative
+                );
+            }
+
+            let labels = label_set(self.labels, values, None);
+            let _ = writeln!(text, "{}_sum{} {}", self.name, labels, observations.sum);
+            let _ = writeln!(text, "{}_count{} {}", self.name, labels, cumulative);
+        }
+    }
+}
+
+fn key(labels: &[&str]) -> Vec<String> {
+    labels.iter().map(|label| label.to_string()).collect()
+}
+
+fn write_header(text: &mut String, name: &str, help: &str, type_: &str) {
+    let _ = writeln!(text, "# HELP {} {}", name, help);
+    let _ = writeln!(text, "# TYPE {} {}", name, type_);
+}
+
+// Formats labels as `{name="value",...}`, with the histogram bucket's `le` label last, if given
+fn label_set(names: &[&str], values: &[String], le: Option<&str>) -> String {
+    let mut pairs = names
+        .iter()
+        .zip(values)
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect::<Vec<_>>();
+
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use crate::metrics::Metrics;
+
+    use std::time::Duration;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+
+        metrics.queries.inc(&["A", "NoError"]);
+        metrics.queries.inc(&["A", "NoError"]);
+        metrics.queries.inc(&["MX", "NXDomain"]);
+        metrics.upstream_failures.inc(&["dns \"quoted\":53"]);
+        metrics.cache_hits.inc(&[]);
+        metrics
+            .request_duration
+            .observe(&["udp"], Duration::from_millis(3));
+        metrics
+            .request_duration
+            .observe(&["udp"], Duration::from_secs(60));
+
+        let text = metrics.render();
+
+        assert!(text.contains("# TYPE queensway_queries_total counter\n"));
+        assert!(text.contains("queensway_queries_total{qtype=\"A\",rcode=\"NoError\"} 2\n"));
+        assert!(text.contains("queensway_queries_total{qtype=\"MX\",rcode=\"NXDomain\"} 1\n"));
+        assert!(text
+            .contains("queensway_upstream_failures_total{upstream=\"dns \\\"quoted\\\":53\"} 1\n"));
+        assert!(text.contains("queensway_cache_hits_total 1\n"));
+        assert!(text.contains("queensway_cache_misses_total 0\n"));
+
+        assert!(text.contains(
+            "queensway_request_duration_seconds_bucket{transport=\"udp\",le=\"0.0025\"} 0\n"
+        ));
+        assert!(text.contains(
+            "queensway_request_duration_seconds_bucket{transport=\"udp\",le=\"0.005\"} 1\n"
+        ));
+        assert!(text.contains(
+            "queensway_request_duration_seconds_bucket{transport=\"udp\",le=\"+Inf\"} 2\n"
+        ));
+        assert!(text.contains("queensway_request_duration_seconds_count{transport=\"udp\"} 2\n"));
+    }
+}
@@ -0,0 +1,241 @@
+use crate::writer::{Sink, Writer};
+
+use std::fs::File;
+use std::io::{BufWriter, Error as IoError, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::warn;
+
+// Packets are raw IPv4 or IPv6, with no link-layer header, which lets both share one capture
+const LINKTYPE_RAW: u32 = 101;
+const SNAPLEN: u32 = 262144;
+
+const UDP: u8 = 17;
+const TTL: u8 = 64;
+
+pub struct Datagram {
+    time: SystemTime,
+    source: SocketAddr,
+    destination: SocketAddr,
+    payload: Vec<u8>,
+}
+
+// Captures datagrams to a file in the classic pcap format, so that they can be inspected in
+// Wireshark. The sockets don't expose the IP and UDP headers the datagrams arrived with, so headers
+// are synthesized from the addresses at either end
+pub type Pcap = Writer<Datagram>;
+
+impl Pcap {
+    // The file is replaced whenever the server starts, as it can only have one header
+    pub fn create(path: &str) -> Result<Self, IoError> {
+        let mut file = BufWriter::new(File::create(path)?);
+
+        let mut header = vec![];
+        header.extend(0xa1b2c3d4u32.to_le_bytes());
+        header.extend(2u16.to_le_bytes());
+        header.extend(4u16.to_le_bytes());
+        // The time zone offset and timestamp accuracy, which are always 0
+        header.extend([0; 8]);
+        header.extend(SNAPLEN.to_le_bytes());
+        header.extend(LINKTYPE_RAW.to_le_bytes());
+        file.write_all(&header)?;
+
+        let capture_file = CaptureFile {
+            path: path.to_string(),
+            file,
+        };
+
+        Writer::spawn("pcap", capture_file)
+    }
+
+    pub fn capture(&self, source: SocketAddr, destination: SocketAddr, payload: &[u8]) {
+        self.write(Datagram {
+            time: SystemTime::now(),
+            source,
+            destination,
+            payload: payload.to_vec(),
+        });
+    }
+}
+
+struct CaptureFile {
+    path: String,
+    file: BufWriter<File>,
+}
+
+impl Sink for CaptureFile {
+    type Item = Datagram;
+
+    fn write(&mut self, datagram: Datagram) {
+        if let Err(error) = self.file.write_all(&record(&datagram)) {
+            warn!("Error writing to capture file {}: {}", self.path, error);
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Err(error) = self.file.flush() {
+            warn!("Error writing to capture file {}: {}", self.path, error);
+        }
+    }
+}
+
+// A pcap record: the time it was captured, its length (twice, as it's never cut short), then the
+// packet itself
+fn record(datagram: &Datagram) -> Vec<u8> {
+    let since_epoch = datagram.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let packet = packet(datagram.source, datagram.destination, &datagram.payload);
+
+    let mut record = vec![];
+    record.extend((since_epoch.as_secs() as u32).to_le_bytes());
+    record.extend(since_epoch.subsec_micros().to_le_bytes());
+    record.extend((packet.len() as u32).to_le_bytes());
+    record.extend((packet.len() as u32).to_le_bytes());
+    record.extend(packet);
+
+    record
+}
+
+// Builds an IP packet holding a UDP datagram. If only one end is IPv6, as happens with sockets
+// bound to an IPv6 address accepting IPv4 clients, the other is given as an IPv4-mapped address
+fn packet(source: SocketAddr, destination: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let (source_ip, destination_ip) = match (source.ip(), destination.ip()) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            (source.octets().to_vec(), destination.octets().to_vec())
+        }
+        (source, destination) => (ipv6_octets(source), ipv6_octets(destination)),
+    };
+
+    // The length of a UDP datagram, header included, must fit in 16 bits, as must that of an IPv4
+    // packet (but not an IPv6 one, whose length leaves out its header)
+    let header_len = if source_ip.len() == 4 { 20 + 8 } else { 8 };
+    let payload = &payload[0..payload.len().min(u16::MAX as usize - header_len)];
+    let udp_len = (8 + payload.len()) as u16;
+
+    let mut udp = vec![];
+    udp.extend(source.port().to_be_bytes());
+    udp.extend(destination.port().to_be_bytes());
+    udp.extend(udp_len.to_be_bytes());
+    udp.extend([0, 0]);
+    udp.extend(payload);
+
+    // The UDP checksum covers a pseudo-header of the addresses, protocol and length, as well as the
+    // datagram itself. A checksum of 0 would mean that none was computed, so it's sent as 0xffff
+    let mut pseudo_header = [&source_ip[..], &destination_ip[..]].concat();
+    pseudo_header.extend([0, UDP]);
+    pseudo_header.extend(udp_len.to_be_bytes());
+    let udp_checksum = match checksum(&[&pseudo_header[..], &udp[..]].concat()) {
+        0 => 0xffff,
+        udp_checksum => udp_checksum,
+    };
+    udp[6..8].copy_from_slice(&udp_checksum.to_be_bytes());
+
+    let mut packet = vec![];
+
+    if source_ip.len() == 4 {
+        packet.extend([0x45, 0]);
+        packet.extend((20 + udp_len).to_be_bytes());
+        // The identification, and the flags and fragment offset, with Don't Fragment set
+        packet.extend([0, 0, 0x40, 0]);
+        packet.extend([TTL, UDP, 0, 0]);
+        packet.extend(&source_ip);
+        packet.extend(&destination_ip);
+
+        let header_checksum = checksum(&packet);
+        packet[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+    } else {
+        packet.extend([0x60, 0, 0, 0]);
+        packet.extend(udp_len.to_be_bytes());
+        packet.extend([UDP, TTL]);
+        packet.extend(&source_ip);
+        packet.extend(&destination_ip);
+    }
+
+    packet.extend(udp);
+
+    packet
+}
+
+fn ipv6_octets(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+// The Internet checksum: the one's complement of the one's complement sum of the 16-bit words, with
+// an odd byte out padded with zero (RFC 1071)
+fn checksum(bytes: &[u8]) -> u16 {
+    let mut sum = bytes
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], word.get(1).copied().unwrap_or(0)]) as u32)
+        .sum::<u32>();
+
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::pcap::{checksum, packet};
+
+    #[test]
+    fn test_packet() {
+        let packet_v4 = packet(
+            "192.0.2.1:5353".parse().unwrap(),
+            "198.51.100.2:53".parse().unwrap(),
+            &[1, 2, 3],
+        );
+
+        assert_eq!(
+            packet_v4,
+            [
+                0x45, 0, 0, 31, 0, 0, 0x40, 0, 64, 17, 0x4e, 0x97, 192, 0, 2, 1, 198, 51, 100, 2,
+                0x14, 0xe9, 0, 53, 0, 11, 0xfa, 0x80, 1, 2, 3,
+            ]
+        );
+
+        // Valid checksums sum to zero, along with what they cover
+        assert_eq!(checksum(&packet_v4[0..20]), 0);
+        let mut pseudo_header = packet_v4[12..20].to_vec();
+        pseudo_header.extend([0, 17, 0, 11]);
+        assert_eq!(
+            checksum(&[&pseudo_header[..], &packet_v4[20..]].concat()),
+            0
+        );
+
+        let packet_v6 = packet(
+            "[2001:db8::1]:5353".parse().unwrap(),
+            "192.0.2.1:53".parse().unwrap(),
+            &[1, 2, 3],
+        );
+
+        assert_eq!(packet_v6.len(), 40 + 8 + 3);
+        assert_eq!(&packet_v6[0..8], &[0x60, 0, 0, 0, 0, 11, 17, 64]);
+        assert_eq!(
+            &packet_v6[24..40],
+            &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 192, 0, 2, 1]
+        );
+
+        // Payloads too long for the lengths in the headers are cut short
+        let long_v4 = packet(
+            "192.0.2.1:5353".parse().unwrap(),
+            "198.51.100.2:53".parse().unwrap(),
+            &[0; 70000],
+        );
+        assert_eq!(long_v4.len(), u16::MAX as usize);
+        assert_eq!(&long_v4[2..4], &[0xff, 0xff]);
+        assert_eq!(checksum(&long_v4[0..20]), 0);
+
+        let long_v6 = packet(
+            "[2001:db8::1]:5353".parse().unwrap(),
+            "[2001:db8::2]:53".parse().unwrap(),
+            &[0; 70000],
+        );
+        assert_eq!(long_v6.len(), 40 + u16::MAX as usize);
+        assert_eq!(&long_v6[4..6], &[0xff, 0xff]);
+    }
+}
@@ -0,0 +1,297 @@
+use crate::pcap::Pcap;
+use crate::protocol::Message;
+
+use rand::Rng;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::warn;
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot::{channel, Sender};
+use tokio::time::sleep;
+
+// Outstanding queries are identified by the upstream they were sent to and the transaction ID they
+// were sent with
+type Key = (SocketAddr, u16);
+
+// How many random transaction IDs to try before concluding that there are none free for an upstream
+const MAX_ID_ATTEMPTS: usize = 64;
+
+// How long to wait after failing to receive on a socket before trying again, so that an error that
+// persists doesn't leave the receiving task spinning and flooding the log
+const RECEIVE_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+// A pool of long-lived egress sockets shared by every query sent upstream over UDP. Each query is
+// sent from a randomly chosen socket with a fresh random transaction ID, and `receive` routes
+// replies back to the waiting queries, with the client's original ID restored. If a capture is
+// given, every datagram sent and received is written to it, as it appeared on the wire
+pub struct SocketPool {
+    sockets: Vec<UdpSocket>,
+    max_packet_size: usize,
+    pending: Mutex<HashMap<Key, Pending>>,
+    capture: Option<Arc<Pcap>>,
+}
+
+struct Pending {
+    socket: usize,
+    query: Message,
+    query_id: [u8; 2],
+    sender: Sender<(Message, Vec<u8>)>,
+}
+
+// Forgets the outstanding query when its exchange completes or is abandoned (e.g. on timeout), so
+// that late replies are discarded and the ID is freed for reuse
+struct PendingGuard<'a> {
+    pool: &'a SocketPool,
+    key: Key,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.pool.pending.lock().unwrap().remove(&self.key);
+    }
+}
+
+impl SocketPool {
+    pub fn new(
+        sockets: Vec<UdpSocket>,
+        max_packet_size: usize,
+        capture: Option<Arc<Pcap>>,
+    ) -> Self {
+        assert!(!sockets.is_empty());
+
+        Self {
+            sockets,
+            max_packet_size,
+            pending: Mutex::new(HashMap::new()),
+            capture,
+        }
+    }
+
+    pub fn socket_count(&self) -> usize {
+        self.sockets.len()
+    }
+
+    // Sends the query to the upstream, and waits for the matching reply. `sent` is called once the
+    // query is on its way, with the address of the socket it was sent from
+    pub async fn exchange(
+        &self,
+        upstream_address: SocketAddr,
+        query: &Message,
+        query_bytes: &[u8],
+        sent: impl FnOnce(SocketAddr),
+    ) -> Result<(Message, Vec<u8>), Box<dyn Error + Send + Sync>> {
+        if query_bytes.len() < 2 {
+            return Err("DNS query is too short".into());
+        }
+
+        let (sender, receiver) = channel();
+
+        let (socket, key) = {
+            let mut rng = rand::thread_rng();
+            let socket = rng.gen_range(0..self.sockets.len());
+
+            let mut pending = self.pending.lock().unwrap();
+
+            let id = (0..MAX_ID_ATTEMPTS)
+                .map(|_| rng.gen::<u16>())
+                .find(|&id| !pending.contains_key(&(upstream_address, id)))
+                .ok_or("No transaction IDs free for upstream")?;
+
+            let key = (upstream_address, id);
+
+            pending.insert(
+                key,
+                Pending {
+                    socket,
+                    query: query.clone(),
+                    query_id: [query_bytes[0], query_bytes[1]],
+                    sender,
+                },
+            );
+
+            (socket, key)
+        };
+
+        let _guard = PendingGuard { pool: self, key };
+
+        let mut bytes = query_bytes.to_vec();
+        bytes[0..2].copy_from_slice(&key.1.to_be_bytes());
+
+        self.sockets[socket]
+            .send_to(&bytes, upstream_address)
+            .await?;
+
+        // The socket's address is all that's known of the source, so sockets bound to an
+        // unspecified address appear to send from it
+        if let Ok(local_address) = self.sockets[socket].local_addr() {
+            if let Some(capture) = &self.capture {
+                capture.capture(local_address, upstream_address, &bytes);
+            }
+
+            sent(local_address);
+        }
+
+        // Fallible only in the case that the pool has been dropped
+        Ok(receiver.await?)
+    }
+
+    // Receives replies on one of the sockets, handing each to the query awaiting it. Anyone can
+    // send a datagram to an egress socket, so replies are only accepted if they come from the
+    // upstream the query was sent to, on the socket it was sent from, and match the query; anything
+    // else is discarded, in case it's an attempt to spoof a reply and poison the cache
+    pub async fn receive(&self, socket: usize) {
+        let mut buffer = vec![0; self.max_packet_size];
+
+        loop {
+            let (len, source_address) = match self.sockets[socket].recv_from(&mut buffer).await {
+                Ok(received) => received,
+                Err(error) => {
+                    warn!("Error receiving DNS reply: {}", error);
+                    sleep(RECEIVE_ERROR_BACKOFF).await;
+                    continue;
+                }
+            };
+
+            // Captured before any checks, so that discarded replies can be inspected too
+            if let Some(capture) = &self.capture {
+                if let Ok(local_address) = self.sockets[socket].local_addr() {
+                    capture.capture(source_address, local_address, &buffer[0..len]);
+                }
+            }
+
+            if len < 2 {
+                warn!("Discarding malformed DNS reply from {}", source_address);
+                continue;
+            }
+
+            let id = u16::from_be_bytes([buffer[0], buffer[1]]);
+
+            let mut pending = self.pending.lock().unwrap();
+
+            let query = match pending.get(&(source_address, id)) {
+                Some(query) if query.socket == socket => query,
+                _ => {
+                    warn!(
+                        "Discarding DNS reply from {} to no outstanding query",
+                        source_address
+                    );
+                    continue;
+                }
+            };
+
+            let mut reply_bytes = buffer[0..len].to_vec();
+            reply_bytes[0..2].copy_from_slice(&query.query_id);
+
+            match Message::parse(&reply_bytes) {
+                Ok(reply) if reply.is_reply_to(&query.query) => {
+                    // Infallible, as the key was just found
+                    let query = pending.remove(&(source_address, id)).unwrap();
+
+                    // Fails only if the query has just been abandoned, in which case the reply is
+                    // no longer wanted anyway
+                    let _ = query.sender.send((reply, reply_bytes));
+                }
+                Ok(_) => warn!(
+                    "Discarding DNS reply from {} not matching the outstanding query",
+                    source_address
+                ),
+                Err(error) => warn!(
+                    "Discarding malformed DNS reply from {}: {}",
+                    source_address, error
+                ),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::pool::SocketPool;
+    use crate::protocol::Message;
+    use crate::test_util::{query, reply_to, A};
+
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::net::UdpSocket;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn test_exchange() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream_address = upstream.local_addr().unwrap();
+
+        let sockets = vec![
+            UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+        ];
+        let pool = Arc::new(SocketPool::new(sockets, 512, None));
+
+        for socket in 0..pool.socket_count() {
+            let pool = pool.clone();
+            tokio::spawn(async move { pool.receive(socket).await });
+        }
+
+        // Both clients happen to use the same ID
+        let a = query(7, "a.com", A);
+        let b = query(7, "b.com", A);
+
+        let exchange = |bytes: Vec<u8>| {
+            let pool = pool.clone();
+            tokio::spawn(async move {
+                let query = Message::parse(&bytes).unwrap();
+                pool.exchange(upstream_address, &query, &bytes, |_| ())
+                    .await
+            })
+        };
+
+        let a_reply = exchange(a.clone());
+        let b_reply = exchange(b.clone());
+
+        let mut buffer = [0; 512];
+        let mut received = vec![];
+        for _ in 0..2 {
+            let (len, source_address) = upstream.recv_from(&mut buffer).await.unwrap();
+            received.push((buffer[0..len].to_vec(), source_address));
+        }
+
+        assert_ne!(received[0].0[0..2], received[1].0[0..2]);
+
+        // A spoofed reply from elsewhere, and one to the wrong question, are both ignored
+        let spoofer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for (bytes, source_address) in &received {
+            spoofer
+                .send_to(&reply_to(bytes, 0), source_address)
+                .await
+                .unwrap();
+        }
+
+        let (first, first_address) = &received[0];
+        let (second, second_address) = &received[1];
+        let mut wrong = reply_to(second, 0);
+        wrong[0..2].copy_from_slice(&first[0..2]);
+        upstream.send_to(&wrong, first_address).await.unwrap();
+
+        // Replies are routed back regardless of the order in which they arrive
+        upstream
+            .send_to(&reply_to(second, 0), second_address)
+            .await
+            .unwrap();
+        upstream
+            .send_to(&reply_to(first, 0), first_address)
+            .await
+            .unwrap();
+
+        let wait = Duration::from_secs(5);
+        let (_, a_reply_bytes) = timeout(wait, a_reply).await.unwrap().unwrap().unwrap();
+        let (_, b_reply_bytes) = timeout(wait, b_reply).await.unwrap().unwrap().unwrap();
+
+        assert_eq!(a_reply_bytes, reply_to(&a, 0));
+        assert_eq!(b_reply_bytes, reply_to(&b, 0));
+    }
+}
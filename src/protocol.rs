@@ -1,28 +1,38 @@
+use std::collections::HashMap;
 use std::fmt::{Display, Formatter, Result as FmtResult};
 use std::net::{Ipv4Addr, Ipv6Addr};
 use std::str::FromStr;
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-struct RecordType {
+// The UDP payload size advertised in EDNS OPT pseudo-records, which is small enough to avoid IP
+// fragmentation on practically every path
+const EDNS_UDP_PAYLOAD_SIZE: u16 = 1232;
+
+// Identifies what a query asks, so that queries asking the same thing can be matched. Names are
+// compared case-insensitively, so they're lowercased
+pub type QuestionKey = (Name, RecordType, RecordClass);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RecordType {
     value: u16,
 }
 
 impl RecordType {
     const A: Self = Self::new(1);
-    const AAAA: Self = Self::new(28);
+    const NS: Self = Self::new(2);
     const CNAME: Self = Self::new(5);
-
-    #[cfg(test)]
+    const SOA: Self = Self::new(6);
+    const PTR: Self = Self::new(12);
+    const MX: Self = Self::new(15);
+    const AAAA: Self = Self::new(28);
     const OPT: Self = Self::new(41);
+    const ANY: Self = Self::new(255);
 
     pub const fn new(value: u16) -> Self {
         Self { value }
     }
-}
 
-impl Display for RecordType {
-    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
-        let name = match self.value {
+    pub fn name(&self) -> &'static str {
+        match self.value {
             0 => "Reserved",
             1 => "A",
             2 => "NS",
@@ -120,23 +130,64 @@ impl Display for RecordType {
             32770..=65279 => "Unassigned",
             65280..=65534 => "Private use",
             65535 => "Reserved",
-        };
-        write!(fmt, "{} ({})", name, self.value)?;
+        }
+    }
+}
+
+impl FromStr for RecordType {
+    type Err = String;
+
+    // Only the types whose record data can be given in presentation form are accepted
+    fn from_str(type_: &str) -> Result<Self, Self::Err> {
+        match type_.to_ascii_uppercase().as_str() {
+            "A" => Ok(Self::A),
+            "NS" => Ok(Self::NS),
+            "CNAME" => Ok(Self::CNAME),
+            "SOA" => Ok(Self::SOA),
+            "PTR" => Ok(Self::PTR),
+            "MX" => Ok(Self::MX),
+            "AAAA" => Ok(Self::AAAA),
+            _ => Err(format!("unsupported record type `{}`", type_)),
+        }
+    }
+}
+
+impl Display for RecordType {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        write!(fmt, "{} ({})", self.name(), self.value)?;
         Ok(())
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
-struct RecordClass {
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RecordClass {
     value: u16,
 }
 
 impl RecordClass {
-    fn new(value: u16) -> Self {
+    const IN: Self = Self::new(1);
+    const CH: Self = Self::new(3);
+    const HS: Self = Self::new(4);
+    const ANY: Self = Self::new(255);
+
+    const fn new(value: u16) -> Self {
         Self { value }
     }
 }
 
+impl FromStr for RecordClass {
+    type Err = String;
+
+    fn from_str(class: &str) -> Result<Self, Self::Err> {
+        match class.to_ascii_uppercase().as_str() {
+            "IN" => Ok(Self::IN),
+            "CH" => Ok(Self::CH),
+            "HS" => Ok(Self::HS),
+            _ => Err(format!("unsupported record class `{}`", class)),
+        }
+    }
+}
+
 impl Display for RecordClass {
     fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
         let memo = match self.value {
@@ -157,16 +208,28 @@ impl Display for RecordClass {
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
-struct Name {
-    name: Vec<u8>,
+// Labels are kept apart, rather than joined with dots, as a label on the wire may itself contain a
+// dot
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Name {
+    labels: Vec<Vec<u8>>,
 }
 
 impl Name {
+    pub fn to_lowercase(&self) -> Self {
+        Self {
+            labels: self
+                .labels
+                .iter()
+                .map(|label| label.to_ascii_lowercase())
+                .collect(),
+        }
+    }
+
     // Individual domain names must be parsed from the full payload of the DNS message, in order to
     // support compressed labels referencing other names in the message
     fn parse(bytes: &[u8], cursor: &mut usize) -> Result<Self, ParseError> {
-        let mut name = Vec::with_capacity(64);
+        let mut labels = vec![];
 
         loop {
             if *cursor >= bytes.len() {
@@ -177,20 +240,16 @@ impl Name {
 
             if byte == 0 {
                 *cursor += 1;
-                return Ok(Self { name });
+                return Ok(Self { labels });
             }
 
             match byte >> 6 {
                 0 => {
-                    if !name.is_empty() {
-                        name.push(b'.');
-                    }
-
                     if *cursor + 1 + (byte as usize) > bytes.len() {
                         return Err(ParseError::Truncated);
                     }
 
-                    name.extend(&bytes[(*cursor + 1)..(*cursor + 1 + (byte as usize))]);
+                    labels.push(bytes[(*cursor + 1)..(*cursor + 1 + (byte as usize))].to_vec());
                     *cursor += 1 + (byte as usize);
                 }
                 3 => {
@@ -213,33 +272,106 @@ impl Name {
 
                     *cursor += 2;
 
-                    name.extend(&tail.name);
+                    labels.extend(tail.labels);
 
-                    return Ok(Self { name });
+                    return Ok(Self { labels });
                 }
                 _ => return Err(ParseError::Invalid),
             }
         }
     }
+
+    // Writes the name label by label, replacing the longest suffix that has already been written
+    // elsewhere in the message with a compression pointer
+    fn write_to(&self, bytes: &mut Vec<u8>, compressor: &mut Compressor) {
+        for index in 0..self.labels.len() {
+            let suffix = &self.labels[index..];
+
+            if let Some(&offset) = compressor.offsets.get(suffix) {
+                bytes.extend((0b11000000_00000000 | offset).to_be_bytes());
+                return;
+            }
+
+            // Pointers are 14 bits wide, so names written further into the message than that can't
+            // be referenced
+            let offset = bytes.len() - compressor.message_start;
+            if offset < (1 << 14) {
+                compressor.offsets.insert(suffix.to_vec(), offset as u16);
+            }
+
+            let label = &self.labels[index];
+            bytes.push(label.len() as u8);
+            bytes.extend(label);
+        }
+
+        bytes.push(0);
+    }
+}
+
+// Remembers where each name suffix was written in a message being serialized, relative to the start
+// of the message
+struct Compressor {
+    message_start: usize,
+    offsets: HashMap<Vec<Vec<u8>>, u16>,
+}
+
+impl Compressor {
+    fn new(message_start: usize) -> Self {
+        Self {
+            message_start,
+            offsets: HashMap::new(),
+        }
+    }
 }
 
 impl FromStr for Name {
     type Err = ();
 
+    // Names are validated up front so that they can always be written out on the wire; the empty
+    // string (or a lone ".") is the root name
     fn from_str(name: &str) -> Result<Self, Self::Err> {
-        Ok(Self {
-            name: name.as_bytes().to_vec(),
-        })
+        let name = name.strip_suffix('.').unwrap_or(name).as_bytes();
+
+        if name.is_empty() {
+            return Ok(Self { labels: vec![] });
+        }
+
+        let labels = name
+            .split(|&byte| byte == b'.')
+            .map(|label| label.to_vec())
+            .collect::<Vec<_>>();
+
+        let wire_len = 1 + labels.iter().map(|label| 1 + label.len()).sum::<usize>();
+
+        if labels
+            .iter()
+            .any(|label| label.is_empty() || label.len() > 63)
+            || wire_len > 255
+        {
+            return Err(());
+        }
+
+        Ok(Self { labels })
     }
 }
 
+// Dots and backslashes within a label are escaped, so that they can't be mistaken for the dots
+// between labels
 impl Display for Name {
     fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
-        for &byte in self.name.iter() {
-            if byte.is_ascii() && !byte.is_ascii_control() {
-                write!(fmt, "{}", byte as char)?;
-            } else {
-                write!(fmt, "\\x{:02x}", byte)?;
+        for (index, label) in self.labels.iter().enumerate() {
+            if index > 0 {
+                write!(fmt, ".")?;
+            }
+
+            for &byte in label.iter() {
+                if byte == b'.' || byte == b'\\' {
+                    write!(fmt, "\\{}", byte as char)?;
+                } else if byte.is_ascii() && !byte.is_ascii_control() {
+                    write!(fmt, "{}", byte as char)?;
+                } else {
+                    write!(fmt, "\\x{:02x}", byte)?;
+                }
             }
         }
         Ok(())
@@ -257,6 +389,51 @@ impl Ttl {
     }
 }
 
+impl FromStr for Ttl {
+    type Err = String;
+
+    // Accepts either a plain number of seconds, or the same `1d2h3m4s` form in which TTLs are
+    // displayed
+    fn from_str(ttl: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid TTL `{}`", ttl);
+
+        if let Ok(seconds) = ttl.parse() {
+            return Ok(Self::new(seconds));
+        }
+
+        let mut seconds = 0u32;
+        let mut rest = ttl;
+
+        while !rest.is_empty() {
+            let digits = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .ok_or_else(invalid)?;
+
+            let value = rest[0..digits].parse::<u32>().map_err(|_| invalid())?;
+
+            // Infallible, as a non-digit was just found
+            let suffix = rest[digits..].chars().next().unwrap();
+
+            let unit = match suffix.to_ascii_lowercase() {
+                'd' => 86400,
+                'h' => 3600,
+                'm' => 60,
+                's' => 1,
+                _ => return Err(invalid()),
+            };
+
+            seconds = value
+                .checked_mul(unit)
+                .and_then(|value| seconds.checked_add(value))
+                .ok_or_else(invalid)?;
+
+            rest = &rest[digits + suffix.len_utf8()..];
+        }
+
+        Ok(Self::new(seconds))
+    }
+}
+
 impl Display for Ttl {
     fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
         let mut seconds = self.seconds;
@@ -289,10 +466,40 @@ impl Display for Ttl {
 
 #[derive(Clone, Debug, PartialEq)]
 enum Rdata {
-    A { ip: Ipv4Addr },
-    Aaaa { ip: Ipv6Addr },
-    Cname { name: Name },
-    Other { data: Vec<u8> },
+    A {
+        ip: Ipv4Addr,
+    },
+    Aaaa {
+        ip: Ipv6Addr,
+    },
+    Cname {
+        name: Name,
+    },
+    // The record types defined in RFC 1035 whose data contains domain names are decoded in full,
+    // because those names may be compressed against the rest of the message; any other type is
+    // kept as opaque bytes, which is safe to write back out verbatim
+    Ns {
+        name: Name,
+    },
+    Ptr {
+        name: Name,
+    },
+    Mx {
+        preference: u16,
+        exchange: Name,
+    },
+    Soa {
+        mname: Name,
+        rname: Name,
+        serial: u32,
+        refresh: Ttl,
+        retry: Ttl,
+        expire: Ttl,
+        minimum: Ttl,
+    },
+    Other {
+        data: Vec<u8>,
+    },
 }
 
 impl Rdata {
@@ -332,7 +539,7 @@ impl Rdata {
                 let ip = Ipv6Addr::from(raw_ip);
                 Self::Aaaa { ip }
             }
-            RecordType::CNAME => {
+            RecordType::CNAME | RecordType::NS | RecordType::PTR => {
                 let mut name_cursor = *cursor;
                 let name = Name::parse(&bytes[0..*cursor + len], &mut name_cursor)?;
 
@@ -340,7 +547,62 @@ impl Rdata {
                     return Err(ParseError::Extra);
                 }
 
-                Self::Cname { name }
+                match type_ {
+                    RecordType::CNAME => Self::Cname { name },
+                    RecordType::NS => Self::Ns { name },
+                    _ => Self::Ptr { name },
+                }
+            }
+            RecordType::MX => {
+                if len < 2 {
+                    return Err(ParseError::Truncated);
+                }
+
+                let preference = ((bytes[*cursor] as u16) << 8) | (bytes[*cursor + 1] as u16);
+
+                let mut name_cursor = *cursor + 2;
+                let exchange = Name::parse(&bytes[0..*cursor + len], &mut name_cursor)?;
+
+                if name_cursor < *cursor + len {
+                    return Err(ParseError::Extra);
+                }
+
+                Self::Mx {
+                    preference,
+                    exchange,
+                }
+            }
+            RecordType::SOA => {
+                let end = *cursor + len;
+
+                let mut name_cursor = *cursor;
+                let mname = Name::parse(&bytes[0..end], &mut name_cursor)?;
+                let rname = Name::parse(&bytes[0..end], &mut name_cursor)?;
+
+                if name_cursor + 20 > end {
+                    return Err(ParseError::Truncated);
+                }
+
+                if name_cursor + 20 < end {
+                    return Err(ParseError::Extra);
+                }
+
+                let dword = |index: usize| {
+                    ((bytes[index] as u32) << 24)
+                        | ((bytes[index + 1] as u32) << 16)
+                        | ((bytes[index + 2] as u32) << 8)
+                        | (bytes[index + 3] as u32)
+                };
+
+                Self::Soa {
+                    mname,
+                    rname,
+                    serial: dword(name_cursor),
+                    refresh: Ttl::new(dword(name_cursor + 4)),
+                    retry: Ttl::new(dword(name_cursor + 8)),
+                    expire: Ttl::new(dword(name_cursor + 12)),
+                    minimum: Ttl::new(dword(name_cursor + 16)),
+                }
             }
             _ => {
                 let data = bytes[*cursor..*cursor + len].to_vec();
@@ -351,6 +613,129 @@ impl Rdata {
         *cursor += len;
         Ok(rdata)
     }
+
+    // Builds record data from the whitespace-separated fields following the type in a record's
+    // presentation form
+    fn from_fields(type_: RecordType, fields: &[&str]) -> Result<Self, String> {
+        let name = |field: &str| {
+            Name::from_str(field).map_err(|_| format!("invalid domain name `{}`", field))
+        };
+
+        let expect = |count: usize| {
+            if fields.len() == count {
+                Ok(())
+            } else {
+                Err(format!(
+                    "expected {} field(s) of {} record data, found {}",
+                    count,
+                    type_,
+                    fields.len()
+                ))
+            }
+        };
+
+        let rdata = match type_ {
+            RecordType::A => {
+                expect(1)?;
+                let ip = Ipv4Addr::from_str(fields[0])
+                    .map_err(|_| format!("invalid IPv4 address `{}`", fields[0]))?;
+                Self::A { ip }
+            }
+            RecordType::AAAA => {
+                expect(1)?;
+                let ip = Ipv6Addr::from_str(fields[0])
+                    .map_err(|_| format!("invalid IPv6 address `{}`", fields[0]))?;
+                Self::Aaaa { ip }
+            }
+            RecordType::CNAME => {
+                expect(1)?;
+                Self::Cname {
+                    name: name(fields[0])?,
+                }
+            }
+            RecordType::NS => {
+                expect(1)?;
+                Self::Ns {
+                    name: name(fields[0])?,
+                }
+            }
+            RecordType::PTR => {
+                expect(1)?;
+                Self::Ptr {
+                    name: name(fields[0])?,
+                }
+            }
+            RecordType::MX => {
+                expect(2)?;
+                let preference = fields[0]
+                    .parse()
+                    .map_err(|_| format!("invalid MX preference `{}`", fields[0]))?;
+                Self::Mx {
+                    preference,
+                    exchange: name(fields[1])?,
+                }
+            }
+            RecordType::SOA => {
+                expect(7)?;
+                let serial = fields[2]
+                    .parse()
+                    .map_err(|_| format!("invalid SOA serial `{}`", fields[2]))?;
+                Self::Soa {
+                    mname: name(fields[0])?,
+                    rname: name(fields[1])?,
+                    serial,
+                    refresh: Ttl::from_str(fields[3])?,
+                    retry: Ttl::from_str(fields[4])?,
+                    expire: Ttl::from_str(fields[5])?,
+                    minimum: Ttl::from_str(fields[6])?,
+                }
+            }
+            _ => return Err(format!("unsupported record type {}", type_)),
+        };
+
+        Ok(rdata)
+    }
+
+    // Writes the two-byte length prefix followed by the record data itself
+    fn write_to(&self, bytes: &mut Vec<u8>, compressor: &mut Compressor) {
+        let len_index = bytes.len();
+        bytes.extend([0, 0]);
+
+        match self {
+            Self::A { ip } => bytes.extend(ip.octets()),
+            Self::Aaaa { ip } => bytes.extend(ip.octets()),
+            Self::Cname { name } | Self::Ns { name } | Self::Ptr { name } => {
+                name.write_to(bytes, compressor)
+            }
+            Self::Mx {
+                preference,
+                exchange,
+            } => {
+                bytes.extend(preference.to_be_bytes());
+                exchange.write_to(bytes, compressor);
+            }
+            Self::Soa {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => {
+                mname.write_to(bytes, compressor);
+                rname.write_to(bytes, compressor);
+                bytes.extend(serial.to_be_bytes());
+                for ttl in [refresh, retry, expire, minimum] {
+                    bytes.extend(ttl.seconds.to_be_bytes());
+                }
+            }
+            Self::Other { data } => bytes.extend(data),
+        }
+
+        let len = (bytes.len() - len_index - 2) as u16;
+        bytes[len_index..len_index + 2].copy_from_slice(&len.to_be_bytes());
+    }
 }
 
 impl Display for Rdata {
@@ -362,9 +747,30 @@ impl Display for Rdata {
             Self::Aaaa { ip } => {
                 write!(fmt, "{}", ip)?;
             }
-            Self::Cname { name } => {
+            Self::Cname { name } | Self::Ns { name } | Self::Ptr { name } => {
                 write!(fmt, "{}", name)?;
             }
+            Self::Mx {
+                preference,
+                exchange,
+            } => {
+                write!(fmt, "{} {}", preference, exchange)?;
+            }
+            Self::Soa {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => {
+                write!(
+                    fmt,
+                    "{} {} {} {} {} {} {}",
+                    mname, rname, serial, refresh, retry, expire, minimum
+                )?;
+            }
             Self::Other { data } => {
                 for &byte in data.iter() {
                     if byte.is_ascii() && !byte.is_ascii_control() {
@@ -381,7 +787,7 @@ impl Display for Rdata {
 }
 
 #[derive(Clone, Debug, PartialEq)]
-struct Question {
+pub struct Question {
     name: Name,
     type_: RecordType,
     class: RecordClass,
@@ -403,6 +809,24 @@ impl Question {
 
         Ok(Question { name, type_, class })
     }
+
+    pub fn name(&self) -> &Name {
+        &self.name
+    }
+
+    pub fn type_(&self) -> RecordType {
+        self.type_
+    }
+
+    pub fn class(&self) -> &RecordClass {
+        &self.class
+    }
+
+    fn write_to(&self, bytes: &mut Vec<u8>, compressor: &mut Compressor) {
+        self.name.write_to(bytes, compressor);
+        bytes.extend(self.type_.value.to_be_bytes());
+        bytes.extend(self.class.value.to_be_bytes());
+    }
 }
 
 impl Display for Question {
@@ -457,6 +881,80 @@ impl Record {
             rdata,
         })
     }
+
+    fn write_to(&self, bytes: &mut Vec<u8>, compressor: &mut Compressor) {
+        self.name.write_to(bytes, compressor);
+        bytes.extend(self.type_.value.to_be_bytes());
+        bytes.extend(self.class.value.to_be_bytes());
+        bytes.extend(self.ttl.seconds.to_be_bytes());
+        self.rdata.write_to(bytes, compressor);
+    }
+
+    // CNAME records are considered to answer questions of any type, as a resolver would follow them
+    fn answers(&self, question: &Question) -> bool {
+        let type_matches = self.type_ == question.type_
+            || self.type_ == RecordType::CNAME
+            || question.type_ == RecordType::ANY;
+
+        let class_matches = self.class == question.class || question.class == RecordClass::ANY;
+
+        type_matches && class_matches
+    }
+
+    // The record in the presentation form used by zone files, e.g. `example.com 300 IN A 10.0.0.1`,
+    // with unknown types and classes written as in RFC 3597
+    pub fn to_presentation(&self) -> String {
+        let type_ = match self.type_.name() {
+            "Reserved" | "Unassigned" | "Private use" => format!("TYPE{}", self.type_.value),
+            name => name.to_string(),
+        };
+
+        let class = match self.class.value {
+            1 => "IN".to_string(),
+            3 => "CH".to_string(),
+            4 => "HS".to_string(),
+            value => format!("CLASS{}", value),
+        };
+
+        format!(
+            "{} {} {} {} {}",
+            self.name, self.ttl.seconds, class, type_, self.rdata
+        )
+    }
+}
+
+impl FromStr for Record {
+    type Err = String;
+
+    // Records are given in the presentation form used by zone files (RFC 1035 section 5.1), e.g.
+    // `example.com. 300 IN MX 10 mail.example.com.`, where the class may be omitted in favour of IN
+    fn from_str(record: &str) -> Result<Self, Self::Err> {
+        let fields = record.split_whitespace().collect::<Vec<_>>();
+
+        if fields.len() < 4 {
+            return Err(format!("incomplete record `{}`", record));
+        }
+
+        let name = Name::from_str(fields[0])
+            .map_err(|_| format!("invalid domain name `{}`", fields[0]))?;
+        let ttl = Ttl::from_str(fields[1])?;
+
+        let (class, rest) = match RecordClass::from_str(fields[2]) {
+            Ok(class) => (class, &fields[3..]),
+            Err(_) => (RecordClass::IN, &fields[2..]),
+        };
+
+        let type_ = RecordType::from_str(rest[0])?;
+        let rdata = Rdata::from_fields(type_, &rest[1..])?;
+
+        Ok(Self {
+            name,
+            type_,
+            class,
+            ttl,
+            rdata,
+        })
+    }
 }
 
 impl Display for Record {
@@ -470,19 +968,24 @@ impl Display for Record {
     }
 }
 
-struct ResponseCode {
+#[derive(PartialEq)]
+pub struct ResponseCode {
     value: u16,
 }
 
 impl ResponseCode {
+    pub const NO_ERROR: Self = Self::new(0);
+    pub const FORM_ERR: Self = Self::new(1);
+    pub const SERV_FAIL: Self = Self::new(2);
+    pub const NX_DOMAIN: Self = Self::new(3);
+    pub const REFUSED: Self = Self::new(5);
+
     const fn new(value: u16) -> Self {
         Self { value }
     }
-}
 
-impl Display for ResponseCode {
-    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
-        let name = match self.value {
+    pub fn name(&self) -> &'static str {
+        match self.value {
             0 => "NoError",
             1 => "FormErr",
             2 => "ServFail",
@@ -496,17 +999,25 @@ impl Display for ResponseCode {
             10 => "NotZone",
             11 => "DSOTYPENI",
             _ => "Unassigned",
-        };
-        write!(fmt, "{} ({})", name, self.value)?;
+        }
+    }
+}
+
+impl Display for ResponseCode {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        write!(fmt, "{} ({})", self.name(), self.value)?;
         Ok(())
     }
 }
 
-struct OpCode {
+#[derive(PartialEq)]
+pub struct OpCode {
     value: u16,
 }
 
 impl OpCode {
+    pub const QUERY: Self = Self::new(0);
+
     const fn new(value: u16) -> Self {
         Self { value }
     }
@@ -528,8 +1039,8 @@ impl Display for OpCode {
     }
 }
 
-#[derive(Debug, PartialEq)]
-struct Flags {
+#[derive(Clone, Debug, PartialEq)]
+pub struct Flags {
     value: u16,
 }
 
@@ -616,7 +1127,7 @@ impl Display for Flags {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Message {
     id: u16,
     flags: Flags,
@@ -671,14 +1182,7 @@ impl Message {
             cursor: &mut usize,
             parse: fn(&[u8], &mut usize) -> Result<T, ParseError>,
         ) -> Result<Vec<T>, ParseError> {
-            (0..num).fold(Ok(vec![]), |result, _| {
-                result.and_then(|mut vec| {
-                    parse(bytes, cursor).map(|t| {
-                        vec.push(t);
-                        vec
-                    })
-                })
-            })
+            (0..num).map(|_| parse(bytes, cursor)).collect()
         }
 
         let mut cursor = 12;
@@ -698,6 +1202,313 @@ impl Message {
 
         Ok(message)
     }
+
+    pub fn flags(&self) -> &Flags {
+        &self.flags
+    }
+
+    pub fn answers(&self) -> &[Record] {
+        &self.answers
+    }
+
+    // Practically every DNS implementation rejects queries with more than one question, so this
+    // returns the question only if there is exactly one
+    pub fn question(&self) -> Option<&Question> {
+        match &self.questions[..] {
+            [question] => Some(question),
+            _ => None,
+        }
+    }
+
+    // The key of this query's question, if it's a standard query with a single question
+    pub fn question_key(&self) -> Option<QuestionKey> {
+        if self.flags().is_reply() || self.flags().opcode() != OpCode::QUERY {
+            return None;
+        }
+
+        let question = self.question()?;
+
+        Some((
+            question.name().to_lowercase(),
+            question.type_(),
+            question.class().clone(),
+        ))
+    }
+
+    // Builds an authoritative reply to this query out of the given records, keeping those that
+    // answer its question and renaming them to match it. A name with a CNAME can't have any other
+    // data (RFC 2181 section 10.1), so if a CNAME answers, it's the only answer given
+    pub fn answer_from(&self, records: &[Record]) -> Message {
+        let answers = match self.question() {
+            Some(question) => {
+                let matching = records
+                    .iter()
+                    .filter(|record| record.answers(question))
+                    .collect::<Vec<_>>();
+
+                let cname = matching
+                    .iter()
+                    .find(|record| record.type_ == RecordType::CNAME);
+
+                let answers = match cname {
+                    Some(cname) => vec![*cname],
+                    None => matching,
+                };
+
+                answers
+                    .into_iter()
+                    .map(|record| Record {
+                        name: question.name.clone(),
+                        ..record.clone()
+                    })
+                    .collect()
+            }
+            None => vec![],
+        };
+
+        let flags = Flags::from_parts(
+            true,
+            self.flags.opcode(),
+            true,
+            false,
+            self.flags.recursion_desired(),
+            true,
+            ResponseCode::NO_ERROR,
+        );
+
+        Message {
+            id: self.id,
+            flags,
+            questions: self.questions.clone(),
+            answers,
+            authority_rrs: vec![],
+            additional_rrs: vec![],
+        }
+    }
+
+    // A reply can be cached for as long as the shortest TTL among its records. The TTL field of an
+    // EDNS OPT pseudo-record holds flags instead, so it's not counted
+    pub fn min_ttl(&self) -> Option<u32> {
+        self.records()
+            .filter(|record| record.type_ != RecordType::OPT)
+            .map(|record| record.ttl.seconds)
+            .min()
+    }
+
+    // Negative replies (NXDOMAIN, or NOERROR with no answers) can be cached for the lesser of the
+    // TTL of the SOA record in their authority section and its minimum field (RFC 2308 section 5);
+    // without one, they shouldn't be cached at all
+    pub fn negative_ttl(&self) -> Option<u32> {
+        self.authority_rrs
+            .iter()
+            .find_map(|record| match &record.rdata {
+                Rdata::Soa { minimum, .. } => Some(record.ttl.seconds.min(minimum.seconds)),
+                _ => None,
+            })
+    }
+
+    // Caps the TTLs of all records at the given number of seconds
+    pub fn clamp_ttls(&mut self, seconds: u32) {
+        for record in self
+            .answers
+            .iter_mut()
+            .chain(self.authority_rrs.iter_mut())
+            .chain(self.additional_rrs.iter_mut())
+        {
+            if record.type_ != RecordType::OPT {
+                record.ttl = Ttl::new(record.ttl.seconds.min(seconds));
+            }
+        }
+    }
+
+    // Adapts a previously received reply to a new query for the same question, by echoing the new
+    // query's ID and question (whose case may differ) and counting down the TTLs of the reply's
+    // records by the number of seconds for which it has been held. The reply's OPT pseudo-record
+    // describes the upstream, and whether the query that fetched it used EDNS, so it's replaced
+    // with this server's own, given only if the new query carries one (RFC 6891 section 7)
+    pub fn reuse_for(&self, query: &Message, age: u32) -> Message {
+        let flags = Flags::from_parts(
+            self.flags.is_reply(),
+            self.flags.opcode(),
+            self.flags.is_authoritative_answer(),
+            self.flags.is_truncated(),
+            query.flags.recursion_desired(),
+            self.flags.recursion_available(),
+            self.flags.response_code(),
+        );
+
+        let age_record = |record: &Record| {
+            let mut record = record.clone();
+
+            if record.type_ != RecordType::OPT {
+                record.ttl = Ttl::new(record.ttl.seconds.saturating_sub(age));
+            }
+
+            record
+        };
+
+        let mut additional_rrs = self
+            .additional_rrs
+            .iter()
+            .filter(|record| record.type_ != RecordType::OPT)
+            .map(age_record)
+            .collect::<Vec<_>>();
+
+        if let Some(query_opt) = query.opt() {
+            // The TTL field holds the upper bits of the extended response code, which belong to the
+            // reply, then the EDNS version and the DO bit, which is echoed from the query
+            let extended_rcode = self.opt().map_or(0, |opt| opt.ttl.seconds & 0xff00_0000);
+
+            additional_rrs.push(Record {
+                name: Name { labels: vec![] },
+                type_: RecordType::OPT,
+                class: RecordClass::new(EDNS_UDP_PAYLOAD_SIZE),
+                ttl: Ttl::new(extended_rcode | (query_opt.ttl.seconds & 0x8000)),
+                rdata: Rdata::Other { data: vec![] },
+            });
+        }
+
+        Message {
+            id: query.id,
+            flags,
+            questions: query.questions.clone(),
+            answers: self.answers.iter().map(age_record).collect(),
+            authority_rrs: self.authority_rrs.iter().map(age_record).collect(),
+            additional_rrs,
+        }
+    }
+
+    fn opt(&self) -> Option<&Record> {
+        self.additional_rrs
+            .iter()
+            .find(|record| record.type_ == RecordType::OPT)
+    }
+
+    fn records(&self) -> impl Iterator<Item = &Record> {
+        self.answers
+            .iter()
+            .chain(self.authority_rrs.iter())
+            .chain(self.additional_rrs.iter())
+    }
+
+    // Whether this is a reply to the given query: it must carry the same ID, and echo back the same
+    // question, spelled exactly the same way
+    pub fn is_reply_to(&self, query: &Message) -> bool {
+        self.flags.is_reply() && self.id == query.id && self.questions == query.questions
+    }
+
+    // Builds a reply to this query carrying nothing but an error code and its question
+    pub fn error_reply(&self, response_code: ResponseCode) -> Message {
+        let flags = Flags::from_parts(
+            true,
+            self.flags.opcode(),
+            false,
+            false,
+            self.flags.recursion_desired(),
+            true,
+            response_code,
+        );
+
+        Message {
+            id: self.id,
+            flags,
+            questions: self.questions.clone(),
+            answers: vec![],
+            authority_rrs: vec![],
+            additional_rrs: vec![],
+        }
+    }
+
+    // Builds a FORMERR reply to a query that couldn't be parsed, provided that its header is intact
+    // enough to tell that it's a query and what its ID is
+    pub fn format_error(query_bytes: &[u8]) -> Option<Message> {
+        if query_bytes.len() < 12 {
+            return None;
+        }
+
+        let query = Message {
+            id: ((query_bytes[0] as u16) << 8) | (query_bytes[1] as u16),
+            flags: Flags::new(((query_bytes[2] as u16) << 8) | (query_bytes[3] as u16)),
+            questions: vec![],
+            answers: vec![],
+            authority_rrs: vec![],
+            additional_rrs: vec![],
+        };
+
+        // Replying to a reply risks an endless exchange with whoever sent it
+        if query.flags.is_reply() {
+            return None;
+        }
+
+        Some(query.error_reply(ResponseCode::FORM_ERR))
+    }
+
+    // Clients advertise the largest UDP datagram they can receive in the class field of an EDNS OPT
+    // pseudo-record; without one, they can only be assumed to handle the 512 bytes of RFC 1035
+    pub fn max_udp_payload_size(&self) -> usize {
+        let advertised = self.opt().map_or(0, |record| record.class.value as usize);
+
+        advertised.max(512)
+    }
+
+    // Strips this reply down to its header, question and any EDNS OPT pseudo-record, and sets the
+    // TC bit, so that the client knows to retry over TCP
+    pub fn truncated(&self) -> Message {
+        let flags = Flags::from_parts(
+            self.flags.is_reply(),
+            self.flags.opcode(),
+            self.flags.is_authoritative_answer(),
+            true,
+            self.flags.recursion_desired(),
+            self.flags.recursion_available(),
+            self.flags.response_code(),
+        );
+
+        Message {
+            id: self.id,
+            flags,
+            questions: self.questions.clone(),
+            answers: vec![],
+            authority_rrs: vec![],
+            additional_rrs: self
+                .additional_rrs
+                .iter()
+                .filter(|record| record.type_ == RecordType::OPT)
+                .cloned()
+                .collect(),
+        }
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(512);
+        self.write_to(&mut bytes);
+        bytes
+    }
+
+    pub fn write_to(&self, bytes: &mut Vec<u8>) {
+        let mut compressor = Compressor::new(bytes.len());
+
+        let header = [
+            self.id,
+            self.flags.value,
+            self.questions.len() as u16,
+            self.answers.len() as u16,
+            self.authority_rrs.len() as u16,
+            self.additional_rrs.len() as u16,
+        ];
+
+        for word in header {
+            bytes.extend(word.to_be_bytes());
+        }
+
+        for question in self.questions.iter() {
+            question.write_to(bytes, &mut compressor);
+        }
+
+        for record in self.records() {
+            record.write_to(bytes, &mut compressor);
+        }
+    }
 }
 
 impl Display for Message {
@@ -727,8 +1538,10 @@ impl Display for Message {
 #[cfg(test)]
 mod test {
     use crate::protocol::{
-        Flags, Message, Name, Question, Rdata, Record, RecordClass, RecordType, Ttl,
+        Flags, Message, Name, Question, Rdata, Record, RecordClass, RecordType, ResponseCode, Ttl,
+        EDNS_UDP_PAYLOAD_SIZE,
     };
+    use std::net::{Ipv4Addr, Ipv6Addr};
     use std::str::FromStr;
 
     const XKCD_MESSAGE: [u8; 49] = [
@@ -759,9 +1572,401 @@ mod test {
                     type_: RecordType::OPT,
                     class: RecordClass::new(0x1000),
                     ttl: Ttl::new(0),
-                    rdata: Rdata::Other { data: vec![] },
+                    rdata: Rdata::Other {
+                        data: vec![
+                            0x00, 0x0a, 0x00, 0x08, 0x8f, 0x2d, 0xe3, 0x7b, 0x74, 0x5d, 0x6b, 0x4d,
+                        ],
+                    },
                 }],
             }
         );
     }
+
+    #[test]
+    fn test_message_serialize() {
+        let message = Message::parse(&XKCD_MESSAGE).unwrap();
+        assert_eq!(message.serialize(), XKCD_MESSAGE);
+    }
+
+    #[test]
+    fn test_message_round_trip() {
+        let name = |name: &str| Name::from_str(name).unwrap();
+
+        let record = |owner: &str, type_: RecordType, rdata: Rdata| Record {
+            name: name(owner),
+            type_,
+            class: RecordClass::new(0x01),
+            ttl: Ttl::new(300),
+            rdata,
+        };
+
+        let message = Message {
+            id: 0x1234,
+            flags: Flags::new(0x8180),
+            questions: vec![Question {
+                name: name("www.example.com"),
+                type_: RecordType::A,
+                class: RecordClass::new(0x01),
+            }],
+            answers: vec![
+                record(
+                    "www.example.com",
+                    RecordType::CNAME,
+                    Rdata::Cname {
+                        name: name("example.com"),
+                    },
+                ),
+                record(
+                    "example.com",
+                    RecordType::A,
+                    Rdata::A {
+                        ip: Ipv4Addr::new(93, 184, 216, 34),
+                    },
+                ),
+                record(
+                    "example.com",
+                    RecordType::AAAA,
+                    Rdata::Aaaa {
+                        ip: Ipv6Addr::from_str("2606:2800:220:1:248:1893:25c8:1946").unwrap(),
+                    },
+                ),
+                record(
+                    "example.com",
+                    RecordType::MX,
+                    Rdata::Mx {
+                        preference: 10,
+                        exchange: name("mail.example.com"),
+                    },
+                ),
+                record(
+                    "34.216.184.93.in-addr.arpa",
+                    RecordType::PTR,
+                    Rdata::Ptr {
+                        name: name("example.com"),
+                    },
+                ),
+                record(
+                    "example.com",
+                    RecordType::new(16),
+                    Rdata::Other {
+                        data: b"\x0bhello world".to_vec(),
+                    },
+                ),
+            ],
+            authority_rrs: vec![
+                record(
+                    "example.com",
+                    RecordType::NS,
+                    Rdata::Ns {
+                        name: name("a.iana-servers.net"),
+                    },
+                ),
+                record(
+                    "example.com",
+                    RecordType::SOA,
+                    Rdata::Soa {
+                        mname: name("ns.icann.org"),
+                        rname: name("noc.dns.icann.org"),
+                        serial: 2022040501,
+                        refresh: Ttl::new(7200),
+                        retry: Ttl::new(3600),
+                        expire: Ttl::new(1209600),
+                        minimum: Ttl::new(3600),
+                    },
+                ),
+            ],
+            additional_rrs: vec![],
+        };
+
+        assert_eq!(Message::parse(&message.serialize()).unwrap(), message);
+    }
+
+    #[test]
+    fn test_message_serialize_compressed() {
+        let name = |name: &str| Name::from_str(name).unwrap();
+
+        let message = Message {
+            id: 0xbeef,
+            flags: Flags::new(0x8180),
+            questions: vec![Question {
+                name: name("example.com"),
+                type_: RecordType::MX,
+                class: RecordClass::new(0x01),
+            }],
+            answers: vec![Record {
+                name: name("example.com"),
+                type_: RecordType::MX,
+                class: RecordClass::new(0x01),
+                ttl: Ttl::new(60),
+                rdata: Rdata::Mx {
+                    preference: 10,
+                    exchange: name("mail.example.com"),
+                },
+            }],
+            authority_rrs: vec![],
+            additional_rrs: vec![],
+        };
+
+        let bytes = message.serialize();
+
+        #[rustfmt::skip]
+        let expected = [
+            // Header
+            0xbe, 0xef, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+            // Question, with the name written in full at offset 12
+            0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00,
+            0x00, 0x0f, 0x00, 0x01,
+            // Answer, with the owner name pointing back at the question
+            0xc0, 0x0c, 0x00, 0x0f, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3c, 0x00, 0x09,
+            0x00, 0x0a, 0x04, b'm', b'a', b'i', b'l', 0xc0, 0x0c,
+        ];
+
+        assert_eq!(bytes, expected);
+        assert_eq!(Message::parse(&bytes).unwrap(), message);
+    }
+
+    #[test]
+    fn test_message_answer_from() {
+        let query = Message::parse(&XKCD_MESSAGE).unwrap();
+
+        let record = |type_: RecordType, rdata: Rdata| Record {
+            name: Name::from_str("placeholder").unwrap(),
+            type_,
+            class: RecordClass::new(0x01),
+            ttl: Ttl::new(60),
+            rdata,
+        };
+
+        let a = record(
+            RecordType::A,
+            Rdata::A {
+                ip: Ipv4Addr::new(10, 0, 0, 1),
+            },
+        );
+
+        let aaaa = record(
+            RecordType::AAAA,
+            Rdata::Aaaa {
+                ip: Ipv6Addr::LOCALHOST,
+            },
+        );
+
+        let cname = record(
+            RecordType::CNAME,
+            Rdata::Cname {
+                name: Name::from_str("xkcd.net").unwrap(),
+            },
+        );
+
+        let reply = query.answer_from(&[a.clone(), aaaa.clone()]);
+
+        let renamed = |record: Record| Record {
+            name: Name::from_str("xkcd.com").unwrap(),
+            ..record
+        };
+
+        assert_eq!(reply.id, 0x41de);
+        assert!(reply.flags.is_reply());
+        assert!(reply.flags.is_authoritative_answer());
+        assert!(reply.flags.recursion_desired());
+        assert_eq!(reply.flags.response_code().value, 0);
+        assert_eq!(reply.questions, query.questions);
+        assert_eq!(reply.answers, vec![renamed(a.clone())]);
+        assert!(reply.authority_rrs.is_empty());
+        assert!(reply.additional_rrs.is_empty());
+
+        // A CNAME can't share its name with other data, so it's given alone
+        let reply = query.answer_from(&[a, cname.clone(), aaaa]);
+        assert_eq!(reply.answers, vec![renamed(cname)]);
+    }
+
+    #[test]
+    fn test_message_truncated() {
+        let mut message = Message::parse(&XKCD_MESSAGE).unwrap();
+        assert_eq!(message.max_udp_payload_size(), 4096);
+
+        message.answers.push(Record {
+            name: Name::from_str("xkcd.com").unwrap(),
+            type_: RecordType::A,
+            class: RecordClass::new(0x01),
+            ttl: Ttl::new(60),
+            rdata: Rdata::A {
+                ip: Ipv4Addr::new(10, 0, 0, 1),
+            },
+        });
+
+        let truncated = message.truncated();
+
+        assert!(truncated.flags.is_truncated());
+        assert!(truncated.flags.recursion_desired());
+        assert_eq!(truncated.id, message.id);
+        assert_eq!(truncated.questions, message.questions);
+        assert!(truncated.answers.is_empty());
+        assert_eq!(truncated.additional_rrs, message.additional_rrs);
+
+        message.additional_rrs.clear();
+        assert_eq!(message.max_udp_payload_size(), 512);
+    }
+
+    #[test]
+    fn test_message_reuse_for() {
+        let query = Message::parse(&XKCD_MESSAGE).unwrap();
+
+        let mut reply = query.answer_from(&[Record {
+            name: Name::from_str("xkcd.com").unwrap(),
+            type_: RecordType::A,
+            class: RecordClass::new(1),
+            ttl: Ttl::new(60),
+            rdata: Rdata::A {
+                ip: Ipv4Addr::new(10, 0, 0, 1),
+            },
+        }]);
+        reply.additional_rrs.push(Record {
+            name: Name::from_str("").unwrap(),
+            type_: RecordType::OPT,
+            class: RecordClass::new(0x0200),
+            ttl: Ttl::new(0x0100_8000),
+            rdata: Rdata::Other {
+                data: vec![0x00, 0x0a, 0x00, 0x00],
+            },
+        });
+
+        // The upstream's OPT is replaced with this server's, carrying the reply's extended response
+        // code and the query's DO bit
+        let reused = reply.reuse_for(&query, 10);
+        assert_eq!(reused.answers[0].ttl, Ttl::new(50));
+        assert_eq!(
+            reused.additional_rrs,
+            vec![Record {
+                name: Name::from_str("").unwrap(),
+                type_: RecordType::OPT,
+                class: RecordClass::new(EDNS_UDP_PAYLOAD_SIZE),
+                ttl: Ttl::new(0x0100_0000),
+                rdata: Rdata::Other { data: vec![] },
+            }]
+        );
+
+        // Queries without EDNS get no OPT at all
+        let mut plain_query = query.clone();
+        plain_query.additional_rrs.clear();
+        assert!(reply.reuse_for(&plain_query, 0).additional_rrs.is_empty());
+    }
+
+    #[test]
+    fn test_message_is_reply_to() {
+        let query = Message::parse(&XKCD_MESSAGE).unwrap();
+        let reply = query.error_reply(ResponseCode::SERV_FAIL);
+
+        assert!(reply.is_reply_to(&query));
+        assert!(!query.is_reply_to(&query));
+
+        let mut other_id = reply.clone();
+        other_id.id ^= 1;
+        assert!(!other_id.is_reply_to(&query));
+
+        let mut other_name = reply.clone();
+        other_name.questions[0].name = Name::from_str("XKCD.com").unwrap();
+        assert!(!other_name.is_reply_to(&query));
+
+        let mut no_question = reply;
+        no_question.questions.clear();
+        assert!(!no_question.is_reply_to(&query));
+    }
+
+    #[test]
+    fn test_message_format_error() {
+        let reply = Message::format_error(&XKCD_MESSAGE[0..20]).unwrap();
+
+        assert_eq!(reply.id, 0x41de);
+        assert!(reply.flags.is_reply());
+        assert!(reply.flags.recursion_desired());
+        assert_eq!(reply.flags.response_code().value, 1);
+        assert!(reply.questions.is_empty());
+
+        assert!(Message::format_error(&XKCD_MESSAGE[0..11]).is_none());
+
+        let mut reply_bytes = XKCD_MESSAGE;
+        reply_bytes[2] |= 0x80;
+        assert!(Message::format_error(&reply_bytes[0..20]).is_none());
+    }
+
+    #[test]
+    fn test_record_from_str() {
+        let record = Record::from_str("xkcd.com. 1h IN MX 10 mail.xkcd.com.").unwrap();
+        assert_eq!(
+            record,
+            Record {
+                name: Name::from_str("xkcd.com").unwrap(),
+                type_: RecordType::MX,
+                class: RecordClass::new(1),
+                ttl: Ttl::new(3600),
+                rdata: Rdata::Mx {
+                    preference: 10,
+                    exchange: Name::from_str("mail.xkcd.com").unwrap(),
+                },
+            }
+        );
+
+        let record = Record::from_str("xkcd.com 300 aaaa ::1").unwrap();
+        assert_eq!(record.ttl, Ttl::new(300));
+        assert_eq!(record.class, RecordClass::new(1));
+        assert_eq!(
+            record.rdata,
+            Rdata::Aaaa {
+                ip: Ipv6Addr::LOCALHOST
+            }
+        );
+
+        assert!(Record::from_str("xkcd.com 300 IN A").is_err());
+        assert!(Record::from_str("xkcd.com 300 IN A 10.0.0.256").is_err());
+        assert!(Record::from_str("xkcd.com 5x IN A 10.0.0.1").is_err());
+        assert!(Record::from_str("xkcd.com 300 IN TXT hello").is_err());
+
+        assert_eq!(
+            Record::from_str("xkcd.com. 1h IN MX 10 mail.xkcd.com.")
+                .unwrap()
+                .to_presentation(),
+            "xkcd.com 3600 IN MX 10 mail.xkcd.com"
+        );
+    }
+
+    #[test]
+    fn test_name_from_str() {
+        assert_eq!(Name::from_str("").unwrap().labels, Vec::<Vec<u8>>::new());
+        assert_eq!(Name::from_str(".").unwrap().labels, Vec::<Vec<u8>>::new());
+        assert_eq!(
+            Name::from_str("xkcd.com.").unwrap().labels,
+            [b"xkcd".to_vec(), b"com".to_vec()]
+        );
+        assert!(Name::from_str("xkcd..com").is_err());
+        assert!(Name::from_str(&"a".repeat(64)).is_err());
+        assert!(Name::from_str(&["a"; 128].join(".")).is_err());
+    }
+
+    #[test]
+    fn test_name_with_dot() {
+        let message = |name: &[u8]| {
+            let mut bytes = vec![
+                0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            ];
+            bytes.extend(name);
+            bytes.extend([0x00, 0x01, 0x00, 0x01]);
+            bytes
+        };
+
+        // A dot within a label survives the round trip, and is distinct from a dot between labels
+        let dotted = message(b"\x03a.b\x03com\x00");
+        let parsed = Message::parse(&dotted).unwrap();
+        assert_eq!(parsed.serialize(), dotted);
+        assert_eq!(parsed.question().unwrap().name().to_string(), "a\\.b.com");
+
+        let undotted = Message::parse(&message(b"\x01a\x01b\x03com\x00")).unwrap();
+        assert_ne!(
+            parsed.question().unwrap().name(),
+            undotted.question().unwrap().name()
+        );
+
+        let trailing = message(b"\x02a.\x03com\x00");
+        assert_eq!(Message::parse(&trailing).unwrap().serialize(), trailing);
+    }
 }
@@ -0,0 +1,703 @@
+use crate::protocol::Message;
+use crate::writer::{Sink, Writer};
+
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::fs::{remove_file, rename, File, OpenOptions};
+use std::io::{BufWriter, Error as IoError, ErrorKind as IoErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::warn;
+use serde_json::{json, Value};
+
+// Starts every binary log, so that the reader can tell it apart from anything else
+const MAGIC: &[u8; 8] = b"QWLOG\x00\x00\x01";
+
+// The longest a binary entry can be, following its length: the fixed-size fields, the longest
+// client address, and the three fields of up to 65535 bytes
+const MAX_ENTRY_LEN: usize = 8 + 1 + 19 + 4 + 4 + 1 + 3 * (2 + u16::MAX as usize);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    // One JSON object per line
+    Json,
+    // Length-prefixed entries holding the messages in wire format; see `Entry::to_binary`
+    Binary,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueryLogConfig {
+    pub path: String,
+    pub format: LogFormat,
+    // The log is rotated once it reaches `max_bytes`, or once it's been open for `rotate_interval`,
+    // whichever comes first
+    pub max_bytes: Option<u64>,
+    pub rotate_interval: Option<Duration>,
+    // How many rotated logs to keep, from `path.1` (the newest) to `path.{keep}`
+    pub keep: usize,
+}
+
+impl QueryLogConfig {
+    pub fn new(path: String) -> Self {
+        Self {
+            path,
+            format: LogFormat::Json,
+            max_bytes: None,
+            rotate_interval: None,
+            keep: 5,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CacheStatus {
+    Hit,
+    Miss,
+}
+
+// A single exchange with a client. The messages are kept in wire format, and only parsed again
+// once they reach the writer
+#[derive(Debug, PartialEq)]
+pub struct Entry {
+    pub time: SystemTime,
+    pub client: SocketAddr,
+    pub transport: &'static str,
+    pub query_bytes: Vec<u8>,
+    pub reply_bytes: Vec<u8>,
+    pub latency: Duration,
+    pub rule: Option<usize>,
+    pub cache: Option<CacheStatus>,
+    pub upstream: Option<String>,
+}
+
+impl Entry {
+    // Either message may be malformed, as clients can send anything
+    pub fn query(&self) -> Option<Message> {
+        Message::parse(&self.query_bytes).ok()
+    }
+
+    pub fn reply(&self) -> Option<Message> {
+        Message::parse(&self.reply_bytes).ok()
+    }
+
+    fn to_json(&self) -> Value {
+        let query = self.query();
+        let reply = self.reply();
+        let question = query.as_ref().and_then(|query| query.question());
+
+        let answers = reply.as_ref().map_or(vec![], |reply| {
+            reply
+                .answers()
+                .iter()
+                .map(|record| record.to_presentation())
+                .collect()
+        });
+
+        json!({
+            "timestamp": timestamp(self.time),
+            "client": self.client.to_string(),
+            "transport": self.transport,
+            "qname": question.map(|question| question.name().to_string()),
+            "qtype": question.map(|question| question.type_().name()),
+            "rcode": reply.as_ref().map(|reply| reply.flags().response_code().name()),
+            "answers": answers,
+            "upstream": self.upstream,
+            "latency_ms": self.latency.as_micros() as f64 / 1000.0,
+            "rule": self.rule,
+            "cache": self.cache.map(|cache| match cache {
+                CacheStatus::Hit => "hit",
+                CacheStatus::Miss => "miss",
+            }),
+        })
+    }
+
+    // Entries are written as a 32-bit length, followed by these fields, with integers in network
+    // byte order:
+    //
+    // - the time, as a 64-bit number of microseconds since the Unix epoch
+    // - the transport, as 0 for UDP or 1 for TCP
+    // - the client's address, as 4 or 6 for its IP version, its 4 or 16 bytes, then its 16-bit port
+    // - the latency, as a 32-bit number of microseconds
+    // - the rule, as a 32-bit index plus 1, or 0 if none matched
+    // - the cache status, as 0 if the cache wasn't consulted, 1 for a hit or 2 for a miss
+    // - the upstream, as a 16-bit length followed by its address, empty if none was queried
+    // - the query and the reply, each as a 16-bit length followed by the message in wire format
+    fn to_binary(&self) -> Vec<u8> {
+        let mut bytes = vec![0; 4];
+
+        let micros = self
+            .time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros();
+        bytes.extend((micros as u64).to_be_bytes());
+
+        bytes.push(match self.transport {
+            "tcp" => 1,
+            _ => 0,
+        });
+
+        match self.client.ip() {
+            IpAddr::V4(ip) => {
+                bytes.push(4);
+                bytes.extend(ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                bytes.push(6);
+                bytes.extend(ip.octets());
+            }
+        }
+        bytes.extend(self.client.port().to_be_bytes());
+
+        let latency = u32::try_from(self.latency.as_micros()).unwrap_or(u32::MAX);
+        bytes.extend(latency.to_be_bytes());
+
+        let rule = self.rule.map_or(0, |rule| rule as u32 + 1);
+        bytes.extend(rule.to_be_bytes());
+
+        bytes.push(match self.cache {
+            None => 0,
+            Some(CacheStatus::Hit) => 1,
+            Some(CacheStatus::Miss) => 2,
+        });
+
+        // DNS messages are at most 65535 bytes, so only an absurd upstream address can be cut short
+        for field in [
+            self.upstream.as_deref().unwrap_or_default().as_bytes(),
+            &self.query_bytes,
+            &self.reply_bytes,
+        ] {
+            let len = field.len().min(u16::MAX as usize);
+            bytes.extend((len as u16).to_be_bytes());
+            bytes.extend(&field[0..len]);
+        }
+
+        let len = (bytes.len() - 4) as u32;
+        bytes[0..4].copy_from_slice(&len.to_be_bytes());
+
+        bytes
+    }
+
+    // The inverse of `to_binary`, given the bytes that follow the length
+    fn from_binary(mut bytes: &[u8]) -> Result<Self, IoError> {
+        let reader = &mut bytes;
+
+        let micros = u64::from_be_bytes(read_array(reader)?);
+        let time = UNIX_EPOCH + Duration::from_micros(micros);
+
+        let transport = match read_array::<1>(reader)? {
+            [0] => "udp",
+            [1] => "tcp",
+            _ => return Err(invalid_data("unknown transport")),
+        };
+
+        let ip = match read_array::<1>(reader)? {
+            [4] => IpAddr::V4(Ipv4Addr::from(read_array::<4>(reader)?)),
+            [6] => IpAddr::V6(Ipv6Addr::from(read_array::<16>(reader)?)),
+            _ => return Err(invalid_data("unknown IP version")),
+        };
+        let port = u16::from_be_bytes(read_array(reader)?);
+
+        let latency = Duration::from_micros(u32::from_be_bytes(read_array(reader)?) as u64);
+
+        let rule = match u32::from_be_bytes(read_array(reader)?) {
+            0 => None,
+            rule => Some(rule as usize - 1),
+        };
+
+        let cache = match read_array::<1>(reader)? {
+            [0] => None,
+            [1] => Some(CacheStatus::Hit),
+            [2] => Some(CacheStatus::Miss),
+            _ => return Err(invalid_data("unknown cache status")),
+        };
+
+        let upstream = String::from_utf8(read_field(reader)?)
+            .map_err(|_| invalid_data("upstream address is not UTF-8"))?;
+        let query_bytes = read_field(reader)?;
+        let reply_bytes = read_field(reader)?;
+
+        Ok(Self {
+            time,
+            client: SocketAddr::new(ip, port),
+            transport,
+            query_bytes,
+            reply_bytes,
+            latency,
+            rule,
+            cache,
+            upstream: (!upstream.is_empty()).then_some(upstream),
+        })
+    }
+}
+
+// A summary of the exchange, then the query and the reply in full, each ending with a newline
+impl Display for Entry {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        write!(
+            fmt,
+            "{} {} via {} in {:.3} ms",
+            timestamp(self.time),
+            self.client,
+            self.transport.to_ascii_uppercase(),
+            self.latency.as_micros() as f64 / 1000.0
+        )?;
+
+        if let Some(rule) = self.rule {
+            write!(fmt, ", answered by rule {}", rule)?;
+        }
+        match self.cache {
+            Some(CacheStatus::Hit) => write!(fmt, ", answered from the cache")?,
+            Some(CacheStatus::Miss) => write!(fmt, ", not in the cache")?,
+            None => (),
+        }
+        if let Some(upstream) = &self.upstream {
+            write!(fmt, ", proxied to {}", upstream)?;
+        }
+        writeln!(fmt)?;
+
+        match self.query() {
+            Some(query) => write!(fmt, "Query:\n{}", query)?,
+            None => writeln!(fmt, "Malformed query of {} bytes", self.query_bytes.len())?,
+        }
+        match self.reply() {
+            Some(reply) => write!(fmt, "Reply:\n{}", reply)?,
+            None => writeln!(fmt, "Malformed reply of {} bytes", self.reply_bytes.len())?,
+        }
+
+        Ok(())
+    }
+}
+
+// Reads back the entries of a binary log. A final entry cut short, as by the server being killed
+// while writing it, is reported as an error
+pub struct Reader<R> {
+    reader: R,
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(mut reader: R) -> Result<Self, IoError> {
+        match read_array::<8>(&mut reader) {
+            Ok(magic) if &magic == MAGIC => Ok(Self { reader }),
+            Ok(_) => Err(invalid_data("not a binary query log")),
+            Err(error) if error.kind() == IoErrorKind::UnexpectedEof => {
+                Err(invalid_data("not a binary query log"))
+            }
+            Err(error) => Err(error),
+        }
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = Result<Entry, IoError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut len = [0; 4];
+
+        // Reads a single byte first, so that the end of the log isn't taken for a cut-short entry
+        match self.reader.read(&mut len[0..1]) {
+            Ok(0) => return None,
+            Ok(_) => (),
+            Err(error) => return Some(Err(error)),
+        }
+
+        // The length is checked before anything is allocated for the entry, so that a corrupt log
+        // can't exhaust the memory
+        let entry = self
+            .reader
+            .read_exact(&mut len[1..4])
+            .and_then(|()| match u32::from_be_bytes(len) as usize {
+                len if len > MAX_ENTRY_LEN => Err(invalid_data("entry is too long")),
+                len => read_vec(&mut self.reader, len),
+            })
+            .and_then(|bytes| Entry::from_binary(&bytes));
+
+        Some(entry)
+    }
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> Result<[u8; N], IoError> {
+    let mut bytes = [0; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_vec(reader: &mut impl Read, len: usize) -> Result<Vec<u8>, IoError> {
+    let mut bytes = vec![0; len];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+// A 16-bit length followed by that many bytes
+fn read_field(reader: &mut impl Read) -> Result<Vec<u8>, IoError> {
+    let len = u16::from_be_bytes(read_array(reader)?);
+    read_vec(reader, len as usize)
+}
+
+fn invalid_data(message: &str) -> IoError {
+    IoError::new(IoErrorKind::InvalidData, message)
+}
+
+// Writes entries in the configured format from a dedicated thread, rotating the file as configured
+pub type QueryLog = Writer<Entry>;
+
+impl QueryLog {
+    pub fn open(config: QueryLogConfig) -> Result<Self, IoError> {
+        Writer::spawn("query-log", LogFile::open(config)?)
+    }
+}
+
+struct LogFile {
+    config: QueryLogConfig,
+    file: BufWriter<File>,
+    size: u64,
+    opened: Instant,
+}
+
+impl Sink for LogFile {
+    type Item = Entry;
+
+    fn write(&mut self, entry: Entry) {
+        if let Err(error) = self.write_entry(&entry) {
+            warn!("Error writing to query log {}: {}", self.config.path, error);
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Err(error) = self.file.flush() {
+            warn!("Error writing to query log {}: {}", self.config.path, error);
+        }
+    }
+}
+
+impl LogFile {
+    fn open(config: QueryLogConfig) -> Result<Self, IoError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)?;
+        let size = file.metadata()?.len();
+
+        let mut log_file = Self {
+            config,
+            file: BufWriter::new(file),
+            size,
+            opened: Instant::now(),
+        };
+
+        // Entries appended to a file that doesn't start with the header couldn't be read back, as
+        // when the format has been changed from JSON, so such a file is rotated out of the way
+        if log_file.config.format == LogFormat::Binary && size > 0 && !has_magic(&log_file.config)?
+        {
+            warn!(
+                "Rotating query log {} as it isn't a binary query log",
+                log_file.config.path
+            );
+            log_file.rotate()?;
+        }
+
+        Ok(log_file)
+    }
+
+    fn write_entry(&mut self, entry: &Entry) -> Result<(), IoError> {
+        if self.is_due_for_rotation() {
+            self.rotate()?;
+        }
+
+        let bytes = match self.config.format {
+            LogFormat::Json => {
+                let mut line = serde_json::to_vec(&entry.to_json())?;
+                line.push(b'\n');
+                line
+            }
+            LogFormat::Binary if self.size == 0 => {
+                let mut bytes = MAGIC.to_vec();
+                bytes.extend(entry.to_binary());
+                bytes
+            }
+            LogFormat::Binary => entry.to_binary(),
+        };
+
+        self.file.write_all(&bytes)?;
+        self.size += bytes.len() as u64;
+
+        Ok(())
+    }
+
+    fn is_due_for_rotation(&self) -> bool {
+        let too_large = self.config.max_bytes.is_some_and(|max| self.size >= max);
+        let too_old = self
+            .config
+            .rotate_interval
+            .is_some_and(|interval| self.opened.elapsed() >= interval);
+
+        self.size > 0 && (too_large || too_old)
+    }
+
+    // Moves `path.1` to `path.2` and so on, overwriting the oldest, then moves the current log to
+    // `path.1` and starts a new one
+    fn rotate(&mut self) -> Result<(), IoError> {
+        self.file.flush()?;
+
+        let path = &self.config.path;
+
+        if self.config.keep == 0 {
+            remove_file(path)?;
+        } else {
+            for index in (1..self.config.keep).rev() {
+                let from = format!("{}.{}", path, index);
+
+                if Path::new(&from).exists() {
+                    rename(&from, format!("{}.{}", path, index + 1))?;
+                }
+            }
+
+            rename(path, format!("{}.1", path))?;
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.file = BufWriter::new(file);
+        self.size = 0;
+        self.opened = Instant::now();
+
+        Ok(())
+    }
+}
+
+fn has_magic(config: &QueryLogConfig) -> Result<bool, IoError> {
+    match read_array::<8>(&mut File::open(&config.path)?) {
+        Ok(magic) => Ok(&magic == MAGIC),
+        Err(error) if error.kind() == IoErrorKind::UnexpectedEof => Ok(false),
+        Err(error) => Err(error),
+    }
+}
+
+// Formats the time as in RFC 3339, in UTC and to the millisecond, e.g. `2022-05-01T12:34:56.789Z`
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+
+    let seconds = since_epoch.as_secs();
+    let (days, seconds) = (seconds / 86400, seconds % 86400);
+
+    // Converts days since the epoch into a date in the proleptic Gregorian calendar, working in
+    // 400-year eras that start on the 1st of March
+    // (http://howardhinnant.github.io/date_algorithms.html)
+    let days = days + 719468;
+    let era = days / 146097;
+    let day_of_era = days % 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use crate::query_log::{
+        timestamp, CacheStatus, Entry, LogFormat, QueryLog, QueryLogConfig, Reader, MAGIC,
+    };
+    use crate::test_util::temp_path;
+
+    use std::fs::{create_dir_all, read_to_string, remove_dir_all, write, File};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use serde_json::Value;
+
+    #[test]
+    fn test_timestamp() {
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            timestamp(UNIX_EPOCH + Duration::from_millis(951_827_696_789)),
+            "2000-02-29T12:34:56.789Z"
+        );
+        assert_eq!(
+            timestamp(UNIX_EPOCH + Duration::from_secs(4_107_542_399)),
+            "2100-02-28T23:59:59.000Z"
+        );
+    }
+
+    #[test]
+    fn test_query_log() {
+        let directory = temp_path("query-log");
+        create_dir_all(&directory).unwrap();
+        let path = directory
+            .join("queries.jsonl")
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let query_bytes = vec![
+            0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, b'x',
+            b'k', b'c', b'd', 0x03, b'c', b'o', b'm', 0x00, 0x00, 0x01, 0x00, 0x01,
+        ];
+        let mut reply_bytes = query_bytes.clone();
+        reply_bytes[2] = 0x81;
+        reply_bytes[3] = 0x83;
+
+        let entry = || Entry {
+            time: UNIX_EPOCH,
+            client: "127.0.0.1:5353".parse().unwrap(),
+            transport: "udp",
+            query_bytes: query_bytes.clone(),
+            reply_bytes: reply_bytes.clone(),
+            latency: Duration::from_millis(2),
+            rule: None,
+            cache: Some(CacheStatus::Miss),
+            upstream: Some("8.8.8.8:53".to_string()),
+        };
+
+        // Every entry after the first finds the log at its maximum size, so rotates it
+        let config = QueryLogConfig {
+            max_bytes: Some(1),
+            keep: 2,
+            ..QueryLogConfig::new(path.clone())
+        };
+
+        let query_log = QueryLog::open(config).unwrap();
+        for _ in 0..4 {
+            query_log.write(entry());
+        }
+        assert!(query_log.close(Duration::from_secs(5)));
+
+        let line = read_to_string(&path).unwrap();
+        let json = serde_json::from_str::<Value>(&line).unwrap();
+        assert_eq!(json["timestamp"], "1970-01-01T00:00:00.000Z");
+        assert_eq!(json["client"], "127.0.0.1:5353");
+        assert_eq!(json["qname"], "xkcd.com");
+        assert_eq!(json["qtype"], "A");
+        assert_eq!(json["rcode"], "NXDomain");
+        assert_eq!(json["answers"], Value::Array(vec![]));
+        assert_eq!(json["upstream"], "8.8.8.8:53");
+        assert_eq!(json["rule"], Value::Null);
+        assert_eq!(json["cache"], "miss");
+
+        assert_eq!(read_to_string(format!("{}.1", path)).unwrap(), line);
+        assert_eq!(read_to_string(format!("{}.2", path)).unwrap(), line);
+        assert!(!directory.join("queries.jsonl.3").exists());
+
+        remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_binary() {
+        let entries = [
+            Entry {
+                time: UNIX_EPOCH + Duration::from_micros(1_651_408_496_789_012),
+                client: "127.0.0.1:5353".parse().unwrap(),
+                transport: "udp",
+                query_bytes: vec![1, 2, 3],
+                reply_bytes: vec![4, 5],
+                latency: Duration::from_micros(1234),
+                rule: None,
+                cache: Some(CacheStatus::Hit),
+                upstream: None,
+            },
+            Entry {
+                time: UNIX_EPOCH,
+                client: "[::1]:53".parse().unwrap(),
+                transport: "tcp",
+                query_bytes: vec![],
+                reply_bytes: vec![6],
+                latency: Duration::ZERO,
+                rule: Some(0),
+                cache: None,
+                upstream: Some("dns.example:53".to_string()),
+            },
+        ];
+
+        let mut bytes = MAGIC.to_vec();
+        for entry in &entries {
+            bytes.extend(entry.to_binary());
+        }
+
+        let read = Reader::new(&bytes[..])
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(read, entries);
+
+        // An entry cut short is an error, rather than the end of the log
+        let mut reader = Reader::new(&bytes[0..bytes.len() - 1]).unwrap();
+        assert!(reader.next().unwrap().is_ok());
+        assert!(reader.next().unwrap().is_err());
+
+        assert!(Reader::new(&b"{\"client\""[..]).is_err());
+
+        let mut corrupt = MAGIC.to_vec();
+        corrupt.extend(u32::MAX.to_be_bytes());
+        let error = Reader::new(&corrupt[..])
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(error.to_string(), "entry is too long");
+    }
+
+    #[test]
+    fn test_binary_reopen() {
+        let directory = temp_path("binary-query-log");
+        create_dir_all(&directory).unwrap();
+        let path = directory.join("queries.log").to_str().unwrap().to_string();
+
+        let entry = || Entry {
+            time: UNIX_EPOCH,
+            client: "127.0.0.1:5353".parse().unwrap(),
+            transport: "udp",
+            query_bytes: vec![1, 2, 3],
+            reply_bytes: vec![4, 5],
+            latency: Duration::ZERO,
+            rule: None,
+            cache: None,
+            upstream: None,
+        };
+        let config = QueryLogConfig {
+            format: LogFormat::Binary,
+            keep: 1,
+            ..QueryLogConfig::new(path.clone())
+        };
+
+        // A log left over from the JSON format is rotated out of the way, rather than appended to
+        write(&path, "{\"client\":\"127.0.0.1:5353\"}\n").unwrap();
+
+        let query_log = QueryLog::open(config.clone()).unwrap();
+        query_log.write(entry());
+        assert!(query_log.close(Duration::from_secs(5)));
+
+        assert_eq!(
+            read_to_string(format!("{}.1", path)).unwrap(),
+            "{\"client\":\"127.0.0.1:5353\"}\n"
+        );
+
+        // Whereas a binary log is appended to, without repeating the header
+        let query_log = QueryLog::open(config).unwrap();
+        query_log.write(entry());
+        assert!(query_log.close(Duration::from_secs(5)));
+
+        let read = Reader::new(File::open(&path).unwrap())
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(read, [entry(), entry()]);
+
+        remove_dir_all(&directory).unwrap();
+    }
+}
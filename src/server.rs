@@ -1,31 +1,71 @@
+use crate::admission::{Admission, Limiter, Overflow, Ticket};
+use crate::cache::Cache;
+use crate::coalesce::Coalescer;
+use crate::dnstap::{Dnstap, DnstapConfig, Event as DnstapEvent, EventType};
+use crate::fixture::{Recorder, Replayer, UpstreamMode};
 use crate::matcher::Matcher;
-use crate::protocol::Message;
-use crate::protocol::Record;
+use crate::metrics::Metrics;
+use crate::pcap::Pcap;
+use crate::pool::SocketPool;
+use crate::protocol::{Message, OpCode, Record, ResponseCode};
+use crate::query_log::{CacheStatus, Entry as QueryLogEntry, QueryLog, QueryLogConfig};
+use crate::upstream::{Selector, Strategy, Upstream};
+use crate::writer::Writer;
 
 use std::error::Error;
-use std::io::Error as IoError;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
 use std::net::SocketAddr;
 use std::net::UdpSocket as StdUdpSocket;
 use std::str::FromStr;
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
 
 use rayon::{ThreadPool, ThreadPoolBuilder};
-use tokio::net::UdpSocket;
+use tokio::io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpListener, TcpStream, UdpSocket};
 use tokio::sync::oneshot::channel;
-use tokio::sync::Semaphore;
-use tokio::task::spawn;
+use tokio::sync::{watch, Mutex, Semaphore};
+use tokio::task::{spawn, spawn_blocking};
+use tokio::time::error::Elapsed;
+use tokio::time::timeout;
 //use tracing::{info, span, Level};
 use log::{info, warn};
 
 pub struct Config {
     pub bind_address: String,
-    pub upstream_address: String,
+    // Upstreams are tried in the order chosen by `upstream_strategy`, until one replies
+    // successfully or `request_timeout` elapses
+    pub upstreams: Vec<Upstream>,
+    pub upstream_strategy: Strategy,
+    // Exchanges with upstreams can be recorded to a fixture, which can later be replayed in place
+    // of the upstreams, so that the server can be tested without network access
+    pub upstream_mode: UpstreamMode,
+    // Queries are sent upstream from a pool of `egress_sockets` sockets, each bound to
+    // `egress_address`; its port should be left as 0, so that each socket gets a random one
     pub egress_address: String,
+    pub egress_sockets: usize,
     pub max_packet_size: usize,
     pub read_timeout: Duration,
     pub write_timeout: Duration,
+    pub request_timeout: Duration,
+    // How long to wait on shutdown for requests already being answered
+    pub drain_timeout: Duration,
     pub max_concurrent_requests: usize,
+    pub overflow: Overflow,
+    // TCP connections beyond this many are closed as soon as they're accepted
+    pub max_tcp_connections: usize,
+    pub cache_max_entries: usize,
+    pub cache_max_bytes: usize,
+    // Prometheus metrics are served over HTTP at `/metrics` on this address, if it's given
+    pub metrics_address: Option<String>,
+    // Every exchange with a client is logged to a file, as JSON Lines or in a compact binary
+    // format, if this is given
+    pub query_log: Option<QueryLogConfig>,
+    // Client and upstream messages are written out as dnstap, if this is given
+    pub dnstap: Option<DnstapConfig>,
+    // Every UDP datagram exchanged with clients and upstreams is captured to this pcap file, if
+    // it's given. Exchanges over TCP aren't captured
+    pub pcap_path: Option<String>,
     pub rules: Vec<(Matcher, Vec<Record>)>,
 }
 
@@ -33,54 +73,412 @@ impl Default for Config {
     fn default() -> Self {
         Self {
             bind_address: "127.0.0.1:53".to_string(),
-            upstream_address: "8.8.8.8:53".to_string(),
+            upstreams: vec![
+                Upstream::new("8.8.8.8:53".to_string(), Duration::from_secs(2)),
+                Upstream::new("8.8.4.4:53".to_string(), Duration::from_secs(2)),
+            ],
+            upstream_strategy: Strategy::Ordered,
+            upstream_mode: UpstreamMode::Network,
             egress_address: "0.0.0.0:0".to_string(),
+            egress_sockets: 16,
             max_packet_size: 256 * 1024,
             read_timeout: Duration::from_secs(5),
             write_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(5),
+            drain_timeout: Duration::from_secs(5),
             max_concurrent_requests: 100,
+            overflow: Overflow::Queue { limit: 1000 },
+            max_tcp_connections: 1000,
+            cache_max_entries: 10000,
+            cache_max_bytes: 16 * 1024 * 1024,
+            metrics_address: None,
+            query_log: None,
+            dnstap: None,
+            pcap_path: None,
             rules: vec![],
         }
     }
 }
 
-struct Server {
-    config: Config,
-    thread_pool: ThreadPool,
+pub struct Server {
+    settings: RwLock<Arc<Settings>>,
     socket: UdpSocket,
-    semaphore: Semaphore,
+    local_address: SocketAddr,
+    listener: TcpListener,
+    limiter: Limiter,
+    tcp_connections: Arc<Semaphore>,
+    cache: Cache,
+    pool: SocketPool,
+    recorder: Option<Recorder>,
+    replayer: Option<Replayer>,
+    coalescer: Coalescer,
+    metrics: Metrics,
+    metrics_listener: Option<TcpListener>,
+    query_log: Option<QueryLog>,
+    dnstap: Option<Dnstap>,
+    pcap: Option<Arc<Pcap>>,
+    shutdown: watch::Sender<bool>,
+}
+
+// The parts of the server that can be swapped out while it's running. Each request works from the
+// settings that were current when it arrived, so that a reload never affects it halfway through
+struct Settings {
+    config: Config,
+    selector: Selector,
+}
+
+impl Settings {
+    fn new(config: Config) -> Self {
+        let selector = Selector::new(config.upstream_strategy, &config.upstreams);
+        Self { config, selector }
+    }
+}
+
+impl Server {
+    pub async fn bind(config: Config) -> Result<Arc<Self>, Box<dyn Error>> {
+        bind(config).await
+    }
+
+    pub async fn serve(self: Arc<Self>) -> Result<(), Box<dyn Error>> {
+        for socket in 0..self.pool.socket_count() {
+            let server = self.clone();
+            spawn(async move { server.pool.receive(socket).await });
+        }
+
+        tokio::try_join!(
+            serve_udp(self.clone()),
+            serve_tcp(self.clone()),
+            serve_metrics(self.clone())
+        )?;
+
+        self.drain().await;
+
+        // Closing the outputs waits on their writer threads, which mustn't hold up the runtime
+        let server = self.clone();
+        spawn_blocking(move || server.close_outputs()).await?;
+
+        Ok(())
+    }
+
+    // Writes out everything logged so far, reporting anything that had to be discarded. Each output
+    // gets up to the drain timeout to do so, in case it's stuck
+    fn close_outputs(&self) {
+        let drain_timeout = self.settings().config.drain_timeout;
+
+        close_output(self.query_log.as_ref(), "query log entries", drain_timeout);
+        close_output(self.dnstap.as_ref(), "dnstap events", drain_timeout);
+        close_output(self.pcap.as_deref(), "captured datagrams", drain_timeout);
+    }
+
+    // Stops the server accepting new requests, whereupon `serve` returns once the requests already
+    // being answered have finished, or the drain timeout has elapsed
+    pub fn shutdown(&self) {
+        info!("Shutting down");
+        self.shutdown.send_replace(true);
+    }
+
+    // Resolves once shutdown has begun
+    async fn shutting_down(&self) {
+        let mut shutdown = self.shutdown.subscribe();
+
+        while !*shutdown.borrow_and_update() {
+            // Infallible, as the sender lives as long as the server
+            shutdown.changed().await.unwrap();
+        }
+    }
+
+    async fn drain(&self) {
+        let drain_timeout = self.settings().config.drain_timeout;
+
+        match timeout(drain_timeout, self.limiter.idle()).await {
+            Ok(()) => info!("Finished answering requests in flight"),
+            Err(_) => warn!(
+                "Abandoning {} requests still in flight after {:?}",
+                self.limiter.in_flight(),
+                drain_timeout
+            ),
+        }
+
+        let (dropped, refused) = self.limiter.shed();
+        if dropped + refused > 0 {
+            info!(
+                "Shed {} requests by dropping them and {} by refusing them",
+                dropped, refused
+            );
+        }
+    }
+
+    // Replaces the configuration of the running server, without dropping its sockets or any
+    // requests in flight. Settings that only matter when the server binds its sockets or allocates
+    // its cache keep their original values until it's restarted
+    pub fn reload(&self, config: Config) {
+        let mut settings = self.settings.write().unwrap();
+
+        let old = &settings.config;
+        let fixed = [
+            ("bind_address", old.bind_address != config.bind_address),
+            ("upstream_mode", old.upstream_mode != config.upstream_mode),
+            (
+                "egress_address",
+                old.egress_address != config.egress_address,
+            ),
+            (
+                "egress_sockets",
+                old.egress_sockets != config.egress_sockets,
+            ),
+            (
+                "max_concurrent_requests",
+                old.max_concurrent_requests != config.max_concurrent_requests,
+            ),
+            ("overflow", old.overflow != config.overflow),
+            (
+                "max_tcp_connections",
+                old.max_tcp_connections != config.max_tcp_connections,
+            ),
+            (
+                "cache_max_entries",
+                old.cache_max_entries != config.cache_max_entries,
+            ),
+            (
+                "cache_max_bytes",
+                old.cache_max_bytes != config.cache_max_bytes,
+            ),
+            (
+                "metrics_address",
+                old.metrics_address != config.metrics_address,
+            ),
+            ("query_log", old.query_log != config.query_log),
+            ("dnstap", old.dnstap != config.dnstap),
+            ("pcap_path", old.pcap_path != config.pcap_path),
+        ];
+
+        for (key, changed) in fixed {
+            if changed {
+                warn!("The new value of `{}` will only apply after a restart", key);
+            }
+        }
+
+        info!(
+            "Reloaded configuration with {} rules, proxying to {}",
+            config.rules.len(),
+            upstream_addresses(&config)
+        );
+
+        *settings = Arc::new(Settings::new(config));
+    }
+
+    fn settings(&self) -> Arc<Settings> {
+        self.settings.read().unwrap().clone()
+    }
+
+    // Passes an event to dnstap, if it's enabled; the event is only built if so
+    fn tap(&self, event: impl FnOnce() -> DnstapEvent) {
+        if let Some(dnstap) = &self.dnstap {
+            dnstap.write(event());
+        }
+    }
+
+    // Passes a datagram sent or received by the server's own socket to the capture, if it's enabled
+    fn capture(&self, source_address: SocketAddr, destination_address: SocketAddr, payload: &[u8]) {
+        if let Some(pcap) = &self.pcap {
+            pcap.capture(source_address, destination_address, payload);
+        }
+    }
+
+    // The limiter keeps its own counts of requests shed, which are copied in at each scrape
+    fn render_metrics(&self) -> String {
+        let (dropped, refused) = self.limiter.shed();
+        self.metrics.requests_shed.set(&["drop"], dropped);
+        self.metrics.requests_shed.set(&["refuse"], refused);
+
+        self.metrics.render()
+    }
+}
+
+fn upstream_addresses(config: &Config) -> String {
+    config
+        .upstreams
+        .iter()
+        .map(|upstream| upstream.address.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
 }
 
-pub async fn bind_and_serve(config: Config) -> Result<(), Box<dyn Error>> {
+async fn bind(config: Config) -> Result<Arc<Server>, Box<dyn Error>> {
     let thread_pool = ThreadPoolBuilder::new().num_threads(1).build()?;
 
+    let bind_error = |error| format!("Error binding to {}: {}", config.bind_address, error);
+
     let socket = bind_socket(
         &config.bind_address,
         config.read_timeout,
         config.write_timeout,
         &thread_pool,
     )
-    .await?;
+    .await
+    .map_err(bind_error)?;
+
+    let local_address = socket.local_addr()?;
+
+    let listener = TcpListener::bind(&config.bind_address)
+        .await
+        .map_err(bind_error)?;
+
+    let limiter = Limiter::new(config.max_concurrent_requests, config.overflow);
+    let tcp_connections = Arc::new(Semaphore::new(config.max_tcp_connections));
+
+    let cache = Cache::new(config.cache_max_entries, config.cache_max_bytes);
+
+    let mut egress_sockets = vec![];
+    for _ in 0..config.egress_sockets.max(1) {
+        egress_sockets.push(
+            bind_socket(
+                &config.egress_address,
+                config.read_timeout,
+                config.write_timeout,
+                &thread_pool,
+            )
+            .await
+            .map_err(|error| {
+                format!(
+                    "Error binding egress socket to {}: {}",
+                    config.egress_address, error
+                )
+            })?,
+        );
+    }
 
-    let semaphore = Semaphore::new(config.max_concurrent_requests);
+    let pcap = match &config.pcap_path {
+        Some(pcap_path) => {
+            let pcap = Pcap::create(pcap_path)
+                .map_err(|error| format!("Error creating capture file {}: {}", pcap_path, error))?;
 
-    info!("Serving DNS queries via UDP on {} and proxying to {}", config.bind_address, config.upstream_address);
+            info!("Capturing UDP datagrams to {}", pcap_path);
+            Some(Arc::new(pcap))
+        }
+        None => None,
+    };
+
+    let pool = SocketPool::new(egress_sockets, config.max_packet_size, pcap.clone());
+
+    let (recorder, replayer) = match &config.upstream_mode {
+        UpstreamMode::Network => (None, None),
+        UpstreamMode::Record { fixture } => {
+            let recorder = Recorder::create(fixture).map_err(|error| {
+                format!("Error creating upstream fixture {}: {}", fixture, error)
+            })?;
+
+            info!("Recording exchanges with upstreams to {}", fixture);
+            (Some(recorder), None)
+        }
+        UpstreamMode::Replay { fixture } => {
+            let replayer = Replayer::load(fixture).map_err(|error| {
+                format!("Error loading upstream fixture {}: {}", fixture, error)
+            })?;
+
+            info!(
+                "Replaying replies to {} questions from {} in place of upstreams",
+                replayer.question_count(),
+                fixture
+            );
+            (None, Some(replayer))
+        }
+    };
 
-    let server = Arc::new(Server {
-        config,
-        thread_pool,
+    let metrics_listener = match &config.metrics_address {
+        Some(metrics_address) => {
+            let listener = TcpListener::bind(metrics_address).await.map_err(|error| {
+                format!(
+                    "Error binding metrics endpoint to {}: {}",
+                    metrics_address, error
+                )
+            })?;
+
+            info!("Serving metrics via HTTP on {}", metrics_address);
+            Some(listener)
+        }
+        None => None,
+    };
+
+    let query_log = match &config.query_log {
+        Some(query_log_config) => {
+            Some(QueryLog::open(query_log_config.clone()).map_err(|error| {
+                format!(
+                    "Error opening query log {}: {}",
+                    query_log_config.path, error
+                )
+            })?)
+        }
+        None => None,
+    };
+
+    let dnstap = match &config.dnstap {
+        Some(dnstap_config) => Some(
+            Dnstap::open(dnstap_config.clone())
+                .map_err(|error| format!("Error opening dnstap output: {}", error))?,
+        ),
+        None => None,
+    };
+
+    info!(
+        "Serving DNS queries via UDP and TCP on {} and proxying to {}",
+        config.bind_address,
+        upstream_addresses(&config)
+    );
+
+    Ok(Arc::new(Server {
+        settings: RwLock::new(Arc::new(Settings::new(config))),
         socket,
-        semaphore,
-    });
+        local_address,
+        listener,
+        limiter,
+        tcp_connections,
+        cache,
+        pool,
+        recorder,
+        replayer,
+        coalescer: Coalescer::new(),
+        metrics: Metrics::new(),
+        metrics_listener,
+        query_log,
+        dnstap,
+        pcap,
+        shutdown: watch::channel(false).0,
+    }))
+}
+
+async fn serve_udp(server: Arc<Server>) -> Result<(), Box<dyn Error>> {
+    let shutting_down = server.shutting_down();
+    tokio::pin!(shutting_down);
 
     loop {
         let server = server.clone();
 
-        let mut buffer = vec![0; server.config.max_packet_size];
-        let (len, source_address) = server.socket.recv_from(&mut buffer).await?;
+        let mut buffer = vec![0; server.settings().config.max_packet_size];
+
+        let (len, source_address) = tokio::select! {
+            received = server.socket.recv_from(&mut buffer) => received?,
+            _ = &mut shutting_down => return Ok(()),
+        };
+
+        server.capture(source_address, server.local_address, &buffer[0..len]);
+
+        let ticket = match server.limiter.admit() {
+            Admission::Admitted(ticket) => ticket,
+            Admission::Dropped => continue,
+            Admission::Refused => {
+                if let Some(reply) = refusal(&buffer[0..len]) {
+                    // Failing to send the refusal is no worse than dropping the request
+                    if server.socket.send_to(&reply, &source_address).await.is_ok() {
+                        server.capture(server.local_address, source_address, &reply);
+                    }
+                }
+                continue;
+            }
+        };
 
         spawn(async move {
-            match serve_request(source_address, buffer, len, server).await {
+            match serve_udp_request(source_address, buffer, len, ticket, server).await {
                 Ok(()) => (),
                 Err(error) => info!(
                     "Error serving DNS request from {}: {}",
@@ -91,6 +489,163 @@ pub async fn bind_and_serve(config: Config) -> Result<(), Box<dyn Error>> {
     }
 }
 
+async fn serve_tcp(server: Arc<Server>) -> Result<(), Box<dyn Error>> {
+    let shutting_down = server.shutting_down();
+    tokio::pin!(shutting_down);
+
+    loop {
+        let server = server.clone();
+
+        let accepted = tokio::select! {
+            accepted = server.listener.accept() => accepted,
+            _ = &mut shutting_down => return Ok(()),
+        };
+
+        let (stream, source_address) = match accepted {
+            Ok(accepted) => accepted,
+            // Failing to accept a single connection (e.g. because the client has already hung up,
+            // or because we're out of file descriptors) shouldn't bring down the whole server
+            Err(error) => {
+                warn!("Error accepting TCP connection: {}", error);
+                continue;
+            }
+        };
+
+        // Clients hold connections open between requests, so they're limited separately from the
+        // requests being answered
+        let Ok(connection) = server.tcp_connections.clone().try_acquire_owned() else {
+            info!(
+                "Closing DNS connection from {}, as too many are open",
+                source_address
+            );
+            continue;
+        };
+
+        spawn(async move {
+            let _connection = connection;
+
+            match serve_tcp_connection(source_address, stream, server).await {
+                Ok(()) => (),
+                Err(error) => info!(
+                    "Error serving DNS connection from {}: {}",
+                    source_address, error
+                ),
+            }
+        });
+    }
+}
+
+async fn serve_metrics(server: Arc<Server>) -> Result<(), Box<dyn Error>> {
+    let listener = match &server.metrics_listener {
+        Some(listener) => listener,
+        None => return Ok(()),
+    };
+
+    let shutting_down = server.shutting_down();
+    tokio::pin!(shutting_down);
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut shutting_down => return Ok(()),
+        };
+
+        let (stream, source_address) = match accepted {
+            Ok(accepted) => accepted,
+            Err(error) => {
+                warn!("Error accepting metrics connection: {}", error);
+                continue;
+            }
+        };
+
+        let server = server.clone();
+
+        spawn(async move {
+            match serve_metrics_request(stream, &server).await {
+                Ok(()) => (),
+                Err(error) => info!(
+                    "Error serving metrics request from {}: {}",
+                    source_address, error
+                ),
+            }
+        });
+    }
+}
+
+// Answers a single HTTP request, then closes the connection. Only `GET /metrics` is served, which
+// is all that Prometheus needs
+async fn serve_metrics_request(
+    mut stream: TcpStream,
+    server: &Server,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let settings = server.settings();
+
+    let request_line = timeout(
+        settings.config.read_timeout,
+        read_http_request_line(&mut stream),
+    )
+    .await??;
+
+    let (status, headers, body) = match request_line.split(' ').collect::<Vec<_>>()[..] {
+        ["GET", "/metrics", _] => (
+            "200 OK",
+            "Content-Type: text/plain; version=0.0.4\r\n",
+            server.render_metrics(),
+        ),
+        [_, "/metrics", _] => (
+            "405 Method Not Allowed",
+            "Allow: GET\r\n",
+            "Method not allowed\n".to_string(),
+        ),
+        _ => ("404 Not Found", "", "Not found\n".to_string()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        headers,
+        body.len(),
+        body
+    );
+
+    timeout(
+        settings.config.write_timeout,
+        stream.write_all(response.as_bytes()),
+    )
+    .await??;
+
+    Ok(())
+}
+
+// Reads the head of an HTTP request, up to the blank line that ends it, and returns its first line.
+// Headers are ignored, as is any body
+async fn read_http_request_line<R: AsyncRead + Unpin>(reader: &mut R) -> Result<String, IoError> {
+    const MAX_HEAD_SIZE: usize = 8 * 1024;
+
+    let mut head = vec![];
+    let mut buffer = [0; 1024];
+
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        if head.len() > MAX_HEAD_SIZE {
+            return Err(IoError::new(
+                IoErrorKind::InvalidData,
+                "HTTP request head is too long",
+            ));
+        }
+
+        let len = reader.read(&mut buffer).await?;
+        if len == 0 {
+            return Err(IoErrorKind::UnexpectedEof.into());
+        }
+
+        head.extend(&buffer[0..len]);
+    }
+
+    let head = String::from_utf8_lossy(&head);
+
+    Ok(head.lines().next().unwrap_or_default().to_string())
+}
+
 async fn bind_socket(
     bind_address: &str,
     read_timeout: Duration,
@@ -107,6 +662,9 @@ async fn bind_socket(
             let std_socket = StdUdpSocket::bind(bind_address)?;
             std_socket.set_read_timeout(Some(read_timeout))?;
             std_socket.set_write_timeout(Some(write_timeout))?;
+            // Tokio requires sockets to be in non-blocking mode; a blocking read would otherwise
+            // stall the whole runtime thread
+            std_socket.set_nonblocking(true)?;
             Ok(std_socket)
         };
 
@@ -121,52 +679,1144 @@ async fn bind_socket(
     Ok(socket)
 }
 
-async fn serve_request(
+#[derive(Clone, Copy, PartialEq)]
+enum Transport {
+    Udp,
+    Tcp,
+}
+
+impl Transport {
+    fn name(self) -> &'static str {
+        match self {
+            Transport::Udp => "udp",
+            Transport::Tcp => "tcp",
+        }
+    }
+}
+
+async fn serve_udp_request(
     source_address: SocketAddr,
-    mut buffer: Vec<u8>,
+    buffer: Vec<u8>,
     len: usize,
+    ticket: Ticket,
     server: Arc<Server>,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let _ = server.semaphore.acquire().await;
+    let _permit = ticket.wait().await?;
+
+    let reply = resolve(source_address, Transport::Udp, &buffer[0..len], &server).await?;
+
+    server.socket.send_to(&reply, &source_address).await?;
+
+    server.capture(server.local_address, source_address, &reply);
+
+    Ok(())
+}
+
+// Clients may pipeline several queries on one TCP connection without waiting for the replies, which
+// are written back in whatever order they complete (RFC 7766 section 6.2.1.1)
+async fn serve_tcp_connection<S: AsyncRead + AsyncWrite + Send + 'static>(
+    source_address: SocketAddr,
+    stream: S,
+    server: Arc<Server>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (mut reader, writer) = split(stream);
+    let writer = Arc::new(Mutex::new(writer));
+
+    let shutting_down = server.shutting_down();
+    tokio::pin!(shutting_down);
+
+    loop {
+        // The connection is closed once the client has been idle for too long, or once the server
+        // starts shutting down; replies to queries already read are still written back
+        let read_timeout = server.settings().config.read_timeout;
+
+        let read = tokio::select! {
+            read = timeout(read_timeout, read_tcp_message(&mut reader)) => read,
+            _ = &mut shutting_down => return Ok(()),
+        };
+
+        let query = match read {
+            Ok(Ok(query)) => query,
+            Ok(Err(error)) if error.kind() == IoErrorKind::UnexpectedEof => return Ok(()),
+            Ok(Err(error)) => return Err(error.into()),
+            Err(_) => {
+                info!("Closing idle DNS connection from {}", source_address);
+                return Ok(());
+            }
+        };
+
+        let ticket = match server.limiter.admit() {
+            Admission::Admitted(ticket) => ticket,
+            // A client waits for a reply to every query it sends over TCP, so rather than leave it
+            // waiting, the connection is closed, prompting it to retry
+            Admission::Dropped => {
+                info!(
+                    "Closing DNS connection from {} to shed a request",
+                    source_address
+                );
+                return Ok(());
+            }
+            Admission::Refused => {
+                if let Some(reply) = refusal(&query) {
+                    let mut writer = writer.lock().await;
+                    timeout(
+                        server.settings().config.write_timeout,
+                        write_tcp_message(&mut *writer, &reply),
+                    )
+                    .await??;
+                }
+                continue;
+            }
+        };
+
+        let server = server.clone();
+        let writer = writer.clone();
+
+        spawn(async move {
+            let serve = async {
+                let _permit = ticket.wait().await?;
+
+                let reply = resolve(source_address, Transport::Tcp, &query, &server).await?;
+
+                let mut writer = writer.lock().await;
+                timeout(
+                    server.settings().config.write_timeout,
+                    write_tcp_message(&mut *writer, &reply),
+                )
+                .await??;
+
+                Ok::<(), Box<dyn Error + Send + Sync>>(())
+            };
+
+            match serve.await {
+                Ok(()) => (),
+                Err(error) => info!(
+                    "Error serving DNS request from {}: {}",
+                    source_address, error
+                ),
+            }
+        });
+    }
+}
+
+// Messages sent over TCP are each prefixed with their length as a 16-bit integer (RFC 1035 section
+// 4.2.2)
+async fn read_tcp_message<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<u8>, IoError> {
+    let mut len = [0; 2];
+    reader.read_exact(&mut len).await?;
+
+    let mut buffer = vec![0; u16::from_be_bytes(len) as usize];
+    reader.read_exact(&mut buffer).await?;
+
+    Ok(buffer)
+}
+
+async fn write_tcp_message<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message: &[u8],
+) -> Result<(), IoError> {
+    let len = u16::try_from(message.len())
+        .map_err(|_| IoError::new(IoErrorKind::InvalidInput, "DNS message is too long"))?;
+
+    let mut framed = Vec::with_capacity(2 + message.len());
+    framed.extend(len.to_be_bytes());
+    framed.extend(message);
+
+    writer.write_all(&framed).await
+}
+
+// Produces the reply to a query that the server is too busy to answer, unless it's too malformed to
+// reply to at all
+fn refusal(query_bytes: &[u8]) -> Option<Vec<u8>> {
+    let query = Message::parse(query_bytes).ok()?;
+
+    if query.flags().is_reply() {
+        return None;
+    }
+
+    Some(query.error_reply(ResponseCode::REFUSED).serialize())
+}
+
+// Closes one of the outputs, reporting how many items it had to discard
+fn close_output<T: Send + 'static>(writer: Option<&Writer<T>>, items: &str, timeout: Duration) {
+    if let Some(writer) = writer {
+        if !writer.close(timeout) {
+            warn!("Abandoning {} not written out after {:?}", items, timeout);
+        }
+
+        let discarded = writer.discarded();
+        if discarded > 0 {
+            warn!(
+                "Discarded {} {} while the writer was behind",
+                discarded, items
+            );
+        }
+    }
+}
+
+// Produces the wire-format reply to a query, either from the configured rules or by proxying it to
+// the upstream
+async fn resolve(
+    source_address: SocketAddr,
+    transport: Transport,
+    query_bytes: &[u8],
+    server: &Server,
+) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let start = Instant::now();
+
+    // The request is answered entirely from the settings current as it starts, even if they're
+    // reloaded partway through
+    let settings = server.settings();
+
+    server.tap(|| DnstapEvent {
+        type_: EventType::ClientQuery,
+        transport: transport.name(),
+        query_address: Some(source_address),
+        response_address: Some(server.local_address),
+        query_time: SystemTime::now(),
+        response_time: None,
+        message: query_bytes.to_vec(),
+    });
+
+    let query = match Message::parse(query_bytes) {
+        Ok(query) => query,
+        Err(error) => {
+            info!(
+                "Received malformed DNS query from {}: {}",
+                source_address, error
+            );
 
+            return match Message::format_error(query_bytes) {
+                Some(reply) => {
+                    let reply_bytes = reply.serialize();
 
-    let query = Message::parse(&buffer[0..len])?;
+                    record_answer(transport, None, &reply, start, server);
+                    log_answer(
+                        source_address,
+                        transport,
+                        query_bytes,
+                        &reply_bytes,
+                        Resolution::default(),
+                        start,
+                        server,
+                    );
+
+                    Ok(reply_bytes)
+                }
+                None => Err(error.into()),
+            };
+        }
+    };
 
     info!("Received DNS query from {}:\n{}", source_address, query);
 
-    let upstream_socket = bind_socket(
-        &server.config.egress_address,
-        server.config.read_timeout,
-        server.config.write_timeout,
-        &server.thread_pool,
-    )
-    .await?;
+    let mut resolution = Resolution::default();
 
-    upstream_socket
-        .send_to(&buffer[0..len], &server.config.upstream_address)
-        .await?; // FIXME: ???
+    let (reply, reply_bytes) =
+        if let Some((rule, reply)) = transform_query(&query, &settings.config.rules) {
+            server.metrics.rule_hits.inc(&[&rule.to_string()]);
+            resolution.rule = Some(rule);
 
-    let (len, _) = upstream_socket.recv_from(&mut buffer).await?;
+            info!(
+                "Answering DNS query from {} locally:\n{}",
+                source_address, reply
+            );
 
-    let reply = Message::parse(&buffer[0..len]).unwrap(); // FIXME
+            let reply_bytes = reply.serialize();
+            (reply, reply_bytes)
+        } else if let Some(reply) = server.cache.get(&query, Instant::now()) {
+            server.metrics.cache_hits.inc(&[]);
+            resolution.cache = Some(CacheStatus::Hit);
 
-    info!(
-        "Received DNS reply from {} to query originating from {}:\n{}",
-        server.config.upstream_address, source_address, reply
+            info!(
+                "Answering DNS query from {} from the cache:\n{}",
+                source_address, reply
+            );
+
+            let reply_bytes = reply.serialize();
+            (reply, reply_bytes)
+        } else {
+            server.metrics.cache_misses.inc(&[]);
+            resolution.cache = Some(CacheStatus::Miss);
+
+            // Concurrent queries for the same question share a single upstream exchange, whose
+            // reply is cached just once. Only the query that made the exchange knows its upstream
+            let upstream = &mut resolution.upstream;
+            let exchange = async {
+                let (reply, reply_bytes, upstream_address) =
+                    query_upstreams(source_address, &query, query_bytes, &settings, server).await?;
+                *upstream = Some(upstream_address);
+
+                server
+                    .cache
+                    .insert(&query, &reply, reply_bytes.len(), Instant::now());
+
+                Ok((reply, reply_bytes))
+            };
+
+            match server.coalescer.coalesce(&query, exchange).await {
+                Ok((reply, reply_bytes)) => (reply, reply_bytes),
+                // The client is always answered, rather than left to time out on its own
+                Err(error) => {
+                    info!(
+                        "Answering DNS query from {} with SERVFAIL: {}",
+                        source_address, error
+                    );
+
+                    let reply = query.error_reply(ResponseCode::SERV_FAIL);
+                    let reply_bytes = reply.serialize();
+                    (reply, reply_bytes)
+                }
+            }
+        };
+
+    record_answer(transport, Some(&query), &reply, start, server);
+
+    // Replies too large for the client to receive in a datagram are cut down to their header and
+    // question, with the TC bit set, so that the client retries over TCP
+    let reply_bytes =
+        if transport == Transport::Udp && reply_bytes.len() > query.max_udp_payload_size() {
+            info!(
+                "Truncating {}-byte DNS reply to {}",
+                reply_bytes.len(),
+                source_address
+            );
+
+            reply.truncated().serialize()
+        } else {
+            reply_bytes
+        };
+
+    log_answer(
+        source_address,
+        transport,
+        query_bytes,
+        &reply_bytes,
+        resolution,
+        start,
+        server,
     );
 
+    Ok(reply_bytes)
+}
+
+// How a query came to be answered, for the query log
+#[derive(Default)]
+struct Resolution {
+    rule: Option<usize>,
+    cache: Option<CacheStatus>,
+    upstream: Option<String>,
+}
+
+// Counts the answer by the query's type and the reply's response code, and times how long it took
+fn record_answer(
+    transport: Transport,
+    query: Option<&Message>,
+    reply: &Message,
+    start: Instant,
+    server: &Server,
+) {
+    let qtype = query
+        .and_then(|query| query.question())
+        .map_or("None", |question| question.type_().name());
+
     server
-        .socket
-        .send_to(&buffer[0..len], &source_address)
+        .metrics
+        .queries
+        .inc(&[qtype, reply.flags().response_code().name()]);
+    server
+        .metrics
+        .request_duration
+        .observe(&[transport.name()], start.elapsed());
+}
+
+// Passes the exchange to the query log and to dnstap, for whichever of them are enabled
+fn log_answer(
+    source_address: SocketAddr,
+    transport: Transport,
+    query_bytes: &[u8],
+    reply_bytes: &[u8],
+    resolution: Resolution,
+    start: Instant,
+    server: &Server,
+) {
+    let now = SystemTime::now();
+
+    server.tap(|| DnstapEvent {
+        type_: EventType::ClientResponse,
+        transport: transport.name(),
+        query_address: Some(source_address),
+        response_address: Some(server.local_address),
+        query_time: now - start.elapsed(),
+        response_time: Some(now),
+        message: reply_bytes.to_vec(),
+    });
+
+    if let Some(query_log) = &server.query_log {
+        query_log.write(QueryLogEntry {
+            time: now,
+            client: source_address,
+            transport: transport.name(),
+            query_bytes: query_bytes.to_vec(),
+            reply_bytes: reply_bytes.to_vec(),
+            latency: start.elapsed(),
+            rule: resolution.rule,
+            cache: resolution.cache,
+            upstream: resolution.upstream,
+        });
+    }
+}
+
+// Answers the query from the fixture when replaying one, and otherwise from the upstreams,
+// recording the exchange if asked to. The reply is returned with the address of the upstream that
+// gave it
+async fn query_upstreams(
+    source_address: SocketAddr,
+    query: &Message,
+    query_bytes: &[u8],
+    settings: &Settings,
+    server: &Server,
+) -> Result<(Message, Vec<u8>, String), Box<dyn Error + Send + Sync>> {
+    if let Some(replayer) = &server.replayer {
+        let (reply, upstream_address) = replayer
+            .reply(query)
+            .ok_or("No reply to the query's question was recorded")?;
+
+        info!(
+            "Replaying DNS reply from {} to query originating from {}:\n{}",
+            upstream_address, source_address, reply
+        );
+
+        let reply_bytes = reply.serialize();
+        return Ok((reply, reply_bytes, upstream_address));
+    }
+
+    let (reply, reply_bytes, upstream_address) =
+        query_network(source_address, query, query_bytes, settings, server).await?;
+
+    if let Some(recorder) = &server.recorder {
+        if let Err(error) = recorder.record(&upstream_address, query_bytes, &reply_bytes) {
+            warn!("Error recording exchange with upstream: {}", error);
+        }
+    }
+
+    Ok((reply, reply_bytes, upstream_address))
+}
+
+// Tries each upstream in the order chosen by the configured strategy, moving on to the next
+// whenever one fails to reply in time, or replies with SERVFAIL or REFUSED, until the overall
+// request timeout elapses. If every upstream fails, the last unsuccessful reply (if any) is relayed
+// to the client. The address of the upstream that replied is returned along with its reply
+async fn query_network(
+    source_address: SocketAddr,
+    query: &Message,
+    query_bytes: &[u8],
+    settings: &Settings,
+    server: &Server,
+) -> Result<(Message, Vec<u8>, String), Box<dyn Error + Send + Sync>> {
+    let deadline = Instant::now() + settings.config.request_timeout;
+
+    let mut last_reply = None;
+
+    for index in settings.selector.order(Instant::now()) {
+        let upstream = &settings.config.upstreams[index];
+
+        let start = Instant::now();
+        let remaining = deadline.saturating_duration_since(start);
+
+        if remaining.is_zero() {
+            break;
+        }
+
+        let labels = [upstream.address.as_str()];
+        server.metrics.upstream_requests.inc(&labels);
+
+        let exchange = query_upstream(
+            source_address,
+            upstream,
+            query,
+            query_bytes,
+            deadline,
+            settings,
+            server,
+        );
+
+        match exchange.await {
+            Ok(Ok((reply, reply_bytes))) => {
+                server
+                    .metrics
+                    .upstream_duration
+                    .observe(&labels, start.elapsed());
+
+                info!(
+                    "Received DNS reply from {} to query originating from {}:\n{}",
+                    upstream.address, source_address, reply
+                );
+
+                let response_code = reply.flags().response_code();
+
+                if response_code != ResponseCode::SERV_FAIL
+                    && response_code != ResponseCode::REFUSED
+                {
+                    settings
+                        .selector
+                        .record(index, start.elapsed(), Instant::now());
+
+                    return Ok((reply, reply_bytes, upstream.address.clone()));
+                }
+
+                server.metrics.upstream_failures.inc(&labels);
+                last_reply = Some((reply, reply_bytes, upstream.address.clone()));
+            }
+            Ok(Err(error)) => {
+                server.metrics.upstream_failures.inc(&labels);
+                info!(
+                    "Error querying upstream {} on behalf of {}: {}",
+                    upstream.address, source_address, error
+                );
+            }
+            Err(_) => {
+                server.metrics.upstream_timeouts.inc(&labels);
+                info!(
+                    "Timed out querying upstream {} on behalf of {}",
+                    upstream.address, source_address
+                );
+            }
+        }
+
+        // Failures count as taking the full timeout
+        settings
+            .selector
+            .record(index, upstream.timeout, Instant::now());
+    }
+
+    last_reply.ok_or_else(|| "No upstream replied successfully".into())
+}
+
+// Queries the upstream over UDP, within its timeout or whatever remains before the deadline, if
+// that's sooner. The outer result is an error only if that exchange times out
+async fn query_upstream(
+    source_address: SocketAddr,
+    upstream: &Upstream,
+    query: &Message,
+    query_bytes: &[u8],
+    deadline: Instant,
+    settings: &Settings,
+    server: &Server,
+) -> Result<Result<(Message, Vec<u8>), Box<dyn Error + Send + Sync>>, Elapsed> {
+    let budget = || {
+        upstream
+            .timeout
+            .min(deadline.saturating_duration_since(Instant::now()))
+    };
+
+    let exchange = query_upstream_udp(upstream, query, query_bytes, server);
+
+    let (mut reply, mut reply_bytes) = match timeout(budget(), exchange).await? {
+        Ok(exchange) => exchange,
+        Err(error) => return Ok(Err(error)),
+    };
+
+    // The upstream had more to say than would fit in a datagram, so ask again over TCP, with a
+    // timeout of its own. If that fails, the client gets the truncated reply, and can retry over
+    // TCP itself
+    if reply.flags().is_truncated() {
+        info!(
+            "Retrying DNS query originating from {} over TCP after truncated reply from {}",
+            source_address, upstream.address
+        );
+
+        let exchange = query_upstream_tcp(upstream, query, query_bytes, settings, server);
+
+        match timeout(budget(), exchange).await {
+            Ok(Ok((tcp_reply, tcp_reply_bytes))) => {
+                reply = tcp_reply;
+                reply_bytes = tcp_reply_bytes;
+            }
+            Ok(Err(error)) => info!(
+                "Error retrying DNS query over TCP to {}: {}",
+                upstream.address, error
+            ),
+            Err(_) => info!(
+                "Timed out retrying DNS query over TCP to {}",
+                upstream.address
+            ),
+        }
+    }
+
+    Ok(Ok((reply, reply_bytes)))
+}
+
+async fn query_upstream_udp(
+    upstream: &Upstream,
+    query: &Message,
+    query_bytes: &[u8],
+    server: &Server,
+) -> Result<(Message, Vec<u8>), Box<dyn Error + Send + Sync>> {
+    let upstream_address = lookup_host(&upstream.address)
+        .await?
+        .next()
+        .ok_or("Upstream address did not resolve")?;
+
+    let query_time = SystemTime::now();
+    let mut local_address = None;
+
+    let sent = |address| {
+        local_address = Some(address);
+
+        server.tap(|| DnstapEvent {
+            type_: EventType::ForwarderQuery,
+            transport: Transport::Udp.name(),
+            query_address: Some(address),
+            response_address: Some(upstream_address),
+            query_time,
+            response_time: None,
+            message: query_bytes.to_vec(),
+        });
+    };
+
+    let (reply, reply_bytes) = server
+        .pool
+        .exchange(upstream_address, query, query_bytes, sent)
         .await?;
 
-    Ok(())
+    server.tap(|| DnstapEvent {
+        type_: EventType::ForwarderResponse,
+        transport: Transport::Udp.name(),
+        query_address: local_address,
+        response_address: Some(upstream_address),
+        query_time,
+        response_time: Some(SystemTime::now()),
+        message: reply_bytes.clone(),
+    });
+
+    Ok((reply, reply_bytes))
 }
 
-async fn transform_query(query: Message) -> (Option<Message>, Vec<Record>) {
-    (Some(query), vec![])
+async fn query_upstream_tcp(
+    upstream: &Upstream,
+    query: &Message,
+    query_bytes: &[u8],
+    settings: &Settings,
+    server: &Server,
+) -> Result<(Message, Vec<u8>), Box<dyn Error + Send + Sync>> {
+    let mut stream = timeout(
+        settings.config.write_timeout,
+        TcpStream::connect(&upstream.address),
+    )
+    .await??;
+
+    let upstream_address = stream.peer_addr().ok();
+    let query_time = SystemTime::now();
+
+    server.tap(|| DnstapEvent {
+        type_: EventType::ForwarderQuery,
+        transport: Transport::Tcp.name(),
+        query_address: stream.local_addr().ok(),
+        response_address: upstream_address,
+        query_time,
+        response_time: None,
+        message: query_bytes.to_vec(),
+    });
+
+    timeout(
+        settings.config.write_timeout,
+        write_tcp_message(&mut stream, query_bytes),
+    )
+    .await??;
+
+    let reply_bytes =
+        timeout(settings.config.read_timeout, read_tcp_message(&mut stream)).await??;
+
+    server.tap(|| DnstapEvent {
+        type_: EventType::ForwarderResponse,
+        transport: Transport::Tcp.name(),
+        query_address: stream.local_addr().ok(),
+        response_address: upstream_address,
+        query_time,
+        response_time: Some(SystemTime::now()),
+        message: reply_bytes.clone(),
+    });
+
+    let reply = Message::parse(&reply_bytes)?;
+
+    if !reply.is_reply_to(query) {
+        return Err("DNS reply does not match the outstanding query".into());
+    }
+
+    Ok((reply, reply_bytes))
+}
+
+// Returns a reply built from the records of the first rule matching the query's question, if any,
+// along with the rule's index; otherwise, the query should be proxied upstream
+fn transform_query(query: &Message, rules: &[(Matcher, Vec<Record>)]) -> Option<(usize, Message)> {
+    if query.flags().is_reply() || query.flags().opcode() != OpCode::QUERY {
+        return None;
+    }
+
+    // Names are compared in lowercase, as clients may randomize the case of their queries
+    let name = query.question()?.name().to_string().to_ascii_lowercase();
+
+    let (index, (_, records)) = rules
+        .iter()
+        .enumerate()
+        .find(|(_, (matcher, _))| matcher.matches(name.clone()))?;
+
+    Some((index, query.answer_from(records)))
 }
 
-async fn transform_response(reply: Message) -> Message {
-    reply
+#[cfg(test)]
+mod test {
+    use crate::config;
+    use crate::fixture::{Recorder, UpstreamMode};
+    use crate::matcher::Matcher;
+    use crate::protocol::{Message, Record};
+    use crate::server::{
+        query_network, read_tcp_message, serve_tcp_connection, write_tcp_message, Config, Server,
+    };
+    use crate::test_util::{query, reply_to, temp_path, A};
+    use crate::upstream::Upstream;
+
+    use std::fs::remove_file;
+    use std::io::ErrorKind as IoErrorKind;
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use tokio::io::{duplex, AsyncWriteExt};
+    use tokio::net::UdpSocket;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+    use tokio::task::spawn;
+    use tokio::time::{sleep, timeout};
+
+    // How a fake upstream treats the queries sent to it
+    #[derive(Clone, Copy)]
+    enum Behaviour {
+        Silent,
+        ServFail,
+        Healthy,
+        // Replies, but only after `SLOW_REPLY_DELAY`
+        Slow,
+    }
+
+    const SLOW_REPLY_DELAY: Duration = Duration::from_millis(300);
+
+    // Starts an upstream for each behaviour, which reports its index whenever it's sent a query
+    async fn upstreams(behaviours: &[Behaviour]) -> (Vec<String>, UnboundedReceiver<usize>) {
+        let (sender, receiver) = unbounded_channel();
+        let mut addresses = vec![];
+
+        for (index, &behaviour) in behaviours.iter().enumerate() {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            addresses.push(socket.local_addr().unwrap().to_string());
+
+            let sender = sender.clone();
+            spawn(async move {
+                let mut buffer = [0; 512];
+                while let Ok((len, source_address)) = socket.recv_from(&mut buffer).await {
+                    let _ = sender.send(index);
+
+                    let reply = match behaviour {
+                        Behaviour::Silent => continue,
+                        Behaviour::ServFail => reply_to(&buffer[0..len], 2),
+                        Behaviour::Healthy => reply_to(&buffer[0..len], 0),
+                        Behaviour::Slow => {
+                            sleep(SLOW_REPLY_DELAY).await;
+                            reply_to(&buffer[0..len], 0)
+                        }
+                    };
+                    let _ = socket.send_to(&reply, source_address).await;
+                }
+            });
+        }
+
+        (addresses, receiver)
+    }
+
+    // A server that sends queries to the given upstreams, with its egress sockets receiving replies
+    async fn forwarder(upstreams: Vec<Upstream>, request_timeout: Duration) -> Arc<Server> {
+        let config = Config {
+            bind_address: "127.0.0.1:0".to_string(),
+            upstreams,
+            egress_address: "127.0.0.1:0".to_string(),
+            egress_sockets: 1,
+            request_timeout,
+            ..Config::default()
+        };
+
+        let server = Server::bind(config).await.unwrap();
+        for socket in 0..server.pool.socket_count() {
+            let server = server.clone();
+            spawn(async move { server.pool.receive(socket).await });
+        }
+        server
+    }
+
+    // A server that answers A queries for xkcd.com from a rule, with the given address
+    fn rule_config(address: &str) -> Config {
+        Config {
+            bind_address: "127.0.0.1:0".to_string(),
+            egress_address: "127.0.0.1:0".to_string(),
+            egress_sockets: 1,
+            rules: vec![(
+                Matcher::Exact {
+                    name: "xkcd.com".to_string(),
+                },
+                vec![Record::from_str(&format!("xkcd.com 60 A {}", address)).unwrap()],
+            )],
+            ..Config::default()
+        }
+    }
+
+    // Asks the server for the A records of xkcd.com, over a connection of its own
+    async fn answers(server: &Arc<Server>) -> Vec<Record> {
+        let (mut client, stream) = duplex(1024);
+        let source_address = "127.0.0.1:5353".parse().unwrap();
+        spawn(serve_tcp_connection(source_address, stream, server.clone()));
+
+        write_tcp_message(&mut client, &query(1, "xkcd.com", A))
+            .await
+            .unwrap();
+        let reply_bytes = read_tcp_message(&mut client).await.unwrap();
+        Message::parse(&reply_bytes).unwrap().answers().to_vec()
+    }
+
+    // Sends a query to the server over UDP, then shuts the server down once the upstream has it.
+    // Returns the socket on which any reply will arrive
+    async fn shut_down_during_query(
+        server: &Server,
+        receiver: &mut UnboundedReceiver<usize>,
+    ) -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket
+            .send_to(&query(7, "xkcd.com", A), server.local_address)
+            .await
+            .unwrap();
+
+        receiver.recv().await.unwrap();
+        server.shutdown();
+        socket
+    }
+
+    fn attempts(receiver: &mut UnboundedReceiver<usize>) -> Vec<usize> {
+        let mut attempts = vec![];
+        while let Ok(index) = receiver.try_recv() {
+            attempts.push(index);
+        }
+        attempts
+    }
+
+    #[tokio::test]
+    async fn test_tcp_framing() {
+        let (mut client, mut server) = duplex(1024);
+
+        write_tcp_message(&mut client, b"xkcd").await.unwrap();
+        assert_eq!(read_tcp_message(&mut server).await.unwrap(), b"xkcd");
+
+        // A zero length is a valid, if empty, message
+        client.write_all(&[0x00, 0x00]).await.unwrap();
+        assert_eq!(read_tcp_message(&mut server).await.unwrap(), b"");
+
+        let error = write_tcp_message(&mut client, &vec![0; 65536])
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), IoErrorKind::InvalidInput);
+
+        // A message cut short, whether in its length prefix or its body, is an error
+        for bytes in [&[0x00][..], &[0x00, 0x04, b'x', b'k']] {
+            let (mut client, mut server) = duplex(1024);
+            client.write_all(bytes).await.unwrap();
+            drop(client);
+
+            let error = read_tcp_message(&mut server).await.unwrap_err();
+            assert_eq!(error.kind(), IoErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tcp_connection() {
+        let read_timeout = Duration::from_millis(200);
+        let config = Config {
+            bind_address: "127.0.0.1:0".to_string(),
+            egress_address: "127.0.0.1:0".to_string(),
+            egress_sockets: 1,
+            read_timeout,
+            rules: vec![(
+                Matcher::Exact {
+                    name: "xkcd.com".to_string(),
+                },
+                vec![Record::from_str("xkcd.com 60 A 10.0.0.1").unwrap()],
+            )],
+            ..Config::default()
+        };
+        let server = Server::bind(config).await.unwrap();
+
+        let (mut client, stream) = duplex(1024);
+        let source_address = "127.0.0.1:5353".parse().unwrap();
+        let connection = spawn(serve_tcp_connection(source_address, stream, server));
+
+        // The empty message can't be answered, but doesn't stop the queries pipelined after it
+        let mut pipelined = vec![0x00, 0x00];
+        for id in [1, 2] {
+            let query = query(id, "xkcd.com", A);
+            pipelined.extend((query.len() as u16).to_be_bytes());
+            pipelined.extend(query);
+        }
+        client.write_all(&pipelined).await.unwrap();
+
+        // Queries answered locally are finished, and so answered, in the order they're read
+        for id in [1, 2] {
+            let reply_bytes = read_tcp_message(&mut client).await.unwrap();
+            assert_eq!(reply_bytes[0..2], u16::to_be_bytes(id));
+            assert_eq!(Message::parse(&reply_bytes).unwrap().answers().len(), 1);
+        }
+
+        // Once the client has been idle for the read timeout, the connection is closed
+        let start = Instant::now();
+        let error = read_tcp_message(&mut client).await.unwrap_err();
+        assert_eq!(error.kind(), IoErrorKind::UnexpectedEof);
+        assert!(start.elapsed() >= read_timeout / 2);
+
+        timeout(Duration::from_secs(5), connection)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_failover() {
+        let behaviours = [Behaviour::Silent, Behaviour::ServFail, Behaviour::Healthy];
+        let (addresses, mut receiver) = upstreams(&behaviours).await;
+
+        let timeout = Duration::from_millis(200);
+        let upstreams = addresses
+            .iter()
+            .map(|address| Upstream::new(address.clone(), timeout))
+            .collect();
+        let server = forwarder(upstreams, Duration::from_secs(5)).await;
+
+        let query_bytes = query(7, "xkcd.com", A);
+        let query = Message::parse(&query_bytes).unwrap();
+        let source_address = "127.0.0.1:5353".parse().unwrap();
+
+        // The silent upstream is given up on after its timeout, and the SERVFAIL passed over
+        let start = Instant::now();
+        let (reply, _, upstream) = query_network(
+            source_address,
+            &query,
+            &query_bytes,
+            &server.settings(),
+            &server,
+        )
+        .await
+        .unwrap();
+
+        assert!(start.elapsed() >= timeout);
+        assert_eq!(reply.flags().response_code().name(), "NoError");
+        assert_eq!(upstream, addresses[2]);
+        assert_eq!(attempts(&mut receiver), [0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_failover_last_reply() {
+        let behaviours = [Behaviour::ServFail, Behaviour::Silent];
+        let (addresses, mut receiver) = upstreams(&behaviours).await;
+
+        let upstreams = addresses
+            .iter()
+            .map(|address| Upstream::new(address.clone(), Duration::from_millis(100)))
+            .collect();
+        let server = forwarder(upstreams, Duration::from_secs(5)).await;
+
+        let query_bytes = query(7, "xkcd.com", A);
+        let query = Message::parse(&query_bytes).unwrap();
+        let source_address = "127.0.0.1:5353".parse().unwrap();
+
+        // With no upstream replying successfully, the client gets the SERVFAIL
+        let (reply, _, upstream) = query_network(
+            source_address,
+            &query,
+            &query_bytes,
+            &server.settings(),
+            &server,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(reply.flags().response_code().name(), "ServFail");
+        assert_eq!(upstream, addresses[0]);
+        assert_eq!(attempts(&mut receiver), [0, 1]);
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout() {
+        let behaviours = [Behaviour::Silent, Behaviour::Healthy];
+        let (addresses, mut receiver) = upstreams(&behaviours).await;
+
+        let upstreams = addresses
+            .iter()
+            .map(|address| Upstream::new(address.clone(), Duration::from_secs(2)))
+            .collect();
+        let request_timeout = Duration::from_millis(300);
+        let server = forwarder(upstreams, request_timeout).await;
+
+        let query_bytes = query(7, "xkcd.com", A);
+        let query = Message::parse(&query_bytes).unwrap();
+        let source_address = "127.0.0.1:5353".parse().unwrap();
+
+        // The silent upstream uses up the whole request timeout, which is shorter than its own, so
+        // the healthy one is never tried
+        let start = Instant::now();
+        let result = query_network(
+            source_address,
+            &query,
+            &query_bytes,
+            &server.settings(),
+            &server,
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert!(start.elapsed() >= request_timeout);
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(attempts(&mut receiver), [0]);
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let server = Server::bind(rule_config("10.0.0.1")).await.unwrap();
+
+        let record = |address| Record::from_str(&format!("xkcd.com 60 A {}", address)).unwrap();
+        assert_eq!(answers(&server).await, [record("10.0.0.1")]);
+
+        // Queries arriving after a reload are answered from the new rules
+        server.reload(rule_config("10.0.0.2"));
+        assert_eq!(answers(&server).await, [record("10.0.0.2")]);
+
+        // An invalid configuration never reaches the server, which keeps answering from the rules
+        // it has
+        let invalid = "[[rules]]\nexact = \"xkcd.com\"\nrecords = [\"xkcd.com 60 A 10.0.0.300\"]";
+        assert!(config::parse(invalid).is_err());
+        assert_eq!(answers(&server).await, [record("10.0.0.2")]);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let (addresses, mut receiver) = upstreams(&[Behaviour::Slow]).await;
+
+        let config = Config {
+            bind_address: "127.0.0.1:0".to_string(),
+            upstreams: vec![Upstream::new(addresses[0].clone(), Duration::from_secs(2))],
+            egress_address: "127.0.0.1:0".to_string(),
+            egress_sockets: 1,
+            drain_timeout: Duration::from_secs(5),
+            ..Config::default()
+        };
+        let server = Server::bind(config).await.unwrap();
+
+        // The query in flight when the server is shut down is still answered before `serve` returns
+        let (served, socket) = tokio::join!(
+            server.clone().serve(),
+            shut_down_during_query(&server, &mut receiver)
+        );
+        served.unwrap();
+
+        let mut buffer = [0; 512];
+        let len = socket.try_recv(&mut buffer).unwrap();
+        let reply_bytes = &buffer[0..len];
+        assert_eq!(reply_bytes[0..2], u16::to_be_bytes(7));
+        assert_eq!(
+            Message::parse(reply_bytes)
+                .unwrap()
+                .flags()
+                .response_code()
+                .name(),
+            "NoError"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drain_timeout() {
+        let (addresses, mut receiver) = upstreams(&[Behaviour::Silent]).await;
+
+        let drain_timeout = Duration::from_millis(200);
+        let config = Config {
+            bind_address: "127.0.0.1:0".to_string(),
+            upstreams: vec![Upstream::new(addresses[0].clone(), Duration::from_secs(5))],
+            egress_address: "127.0.0.1:0".to_string(),
+            egress_sockets: 1,
+            request_timeout: Duration::from_secs(5),
+            drain_timeout,
+            ..Config::default()
+        };
+        let server = Server::bind(config).await.unwrap();
+
+        // A query that would outlast the drain timeout is abandoned once it has elapsed
+        let start = Instant::now();
+        let serve = async {
+            let served = server.clone().serve().await;
+            (served, start.elapsed())
+        };
+        let ((served, elapsed), _) =
+            tokio::join!(serve, shut_down_during_query(&server, &mut receiver));
+        served.unwrap();
+
+        assert!(elapsed >= drain_timeout);
+        assert!(elapsed < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn test_replay() {
+        let path = temp_path("replay.fixture").to_str().unwrap().to_string();
+
+        let recorded_query = query(1, "xkcd.com", A);
+        let mut recorded_reply = reply_to(&recorded_query, 0);
+        recorded_reply[7] = 0x01;
+        recorded_reply.extend([0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2c]);
+        recorded_reply.extend([0x00, 0x04, 0x0a, 0x00, 0x00, 0x01]);
+
+        Recorder::create(&path)
+            .unwrap()
+            .record("192.0.2.53:53", &recorded_query, &recorded_reply)
+            .unwrap();
+
+        // Nothing is sent to the upstreams, which couldn't be reached anyway
+        let config = Config {
+            bind_address: "127.0.0.1:0".to_string(),
+            upstream_mode: UpstreamMode::Replay {
+                fixture: path.clone(),
+            },
+            egress_address: "127.0.0.1:0".to_string(),
+            egress_sockets: 1,
+            ..Config::default()
+        };
+
+        let server = Server::bind(config).await.unwrap();
+        remove_file(&path).unwrap();
+
+        let client = async {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            socket
+                .send_to(&query(7, "xkcd.com", A), server.local_address)
+                .await
+                .unwrap();
+
+            let mut buffer = [0; 512];
+            let received = timeout(Duration::from_secs(5), socket.recv(&mut buffer)).await;
+
+            server.shutdown();
+            buffer[0..received.unwrap().unwrap()].to_vec()
+        };
+
+        let (served, reply_bytes) = tokio::join!(server.clone().serve(), client);
+        served.unwrap();
+
+        let mut expected_reply = recorded_reply;
+        expected_reply[0..2].copy_from_slice(&7u16.to_be_bytes());
+        assert_eq!(
+            Message::parse(&reply_bytes).unwrap(),
+            Message::parse(&expected_reply).unwrap()
+        );
+    }
 }
@@ -0,0 +1,34 @@
+// Helpers shared by the tests of several modules
+
+use std::path::PathBuf;
+
+pub const A: u16 = 1;
+
+// A standard query in wire format, with recursion desired and a single question of class IN
+pub fn query(id: u16, name: &str, type_: u16) -> Vec<u8> {
+    let mut bytes = vec![];
+    bytes.extend(id.to_be_bytes());
+    bytes.extend([0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    for label in name.split('.') {
+        bytes.push(label.len() as u8);
+        bytes.extend(label.as_bytes());
+    }
+    bytes.push(0x00);
+    bytes.extend(type_.to_be_bytes());
+    bytes.extend([0x00, 0x01]);
+    bytes
+}
+
+// A reply to the query with the given response code, and no records
+pub fn reply_to(query: &[u8], response_code: u8) -> Vec<u8> {
+    let mut bytes = query.to_vec();
+    bytes[2] |= 0x80;
+    bytes[3] = 0x80 | response_code;
+    bytes
+}
+
+// A path in the temporary directory that's unique to this process. Tests run concurrently, so each
+// must use its own suffix
+pub fn temp_path(suffix: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("queensway-test-{}-{}", std::process::id(), suffix))
+}
@@ -0,0 +1,221 @@
+use rand::Rng;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub struct Upstream {
+    pub address: String,
+    // How long to wait for this upstream to reply before moving on to the next
+    pub timeout: Duration,
+    // The relative likelihood of this upstream being tried first, under `Strategy::WeightedRandom`
+    pub weight: u32,
+}
+
+impl Upstream {
+    pub fn new(address: String, timeout: Duration) -> Self {
+        Self {
+            address,
+            timeout,
+            weight: 1,
+        }
+    }
+}
+
+// The order in which upstreams are tried for each query. Whichever upstream is tried first, the
+// rest remain available for failover
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Strategy {
+    // Always in the order in which they're configured
+    Ordered,
+    // Starting from each upstream in turn
+    RoundRobin,
+    // Drawn at random, in proportion to their weights
+    WeightedRandom,
+    // Fastest first, by an exponentially weighted moving average of their round-trip times. Slower
+    // upstreams are given the first attempt at a query once per `reprobe_interval`, so that their
+    // averages don't go stale
+    Fastest { reprobe_interval: Duration },
+}
+
+// Tracks whatever state the configured strategy needs in order to choose between the upstreams
+pub struct Selector {
+    strategy: Strategy,
+    weights: Vec<u32>,
+    next: AtomicUsize,
+    stats: Mutex<Vec<Stats>>,
+}
+
+#[derive(Clone, Default)]
+struct Stats {
+    // In seconds
+    average_rtt: Option<f64>,
+    last_attempt: Option<Instant>,
+}
+
+// The weight given to each new round-trip time in the moving average
+const RTT_SMOOTHING: f64 = 0.3;
+
+impl Selector {
+    pub fn new(strategy: Strategy, upstreams: &[Upstream]) -> Self {
+        Self {
+            strategy,
+            weights: upstreams.iter().map(|upstream| upstream.weight).collect(),
+            next: AtomicUsize::new(0),
+            stats: Mutex::new(vec![Stats::default(); upstreams.len()]),
+        }
+    }
+
+    // Returns the indices of the upstreams in the order in which they should be tried
+    pub fn order(&self, now: Instant) -> Vec<usize> {
+        let len = self.weights.len();
+        let mut order = (0..len).collect::<Vec<_>>();
+
+        if len == 0 {
+            return order;
+        }
+
+        match self.strategy {
+            Strategy::Ordered => (),
+            Strategy::RoundRobin => {
+                let first = self.next.fetch_add(1, Ordering::Relaxed) % len;
+                order.rotate_left(first);
+            }
+            Strategy::WeightedRandom => {
+                let mut rng = rand::thread_rng();
+
+                for start in 0..len {
+                    let total = order[start..]
+                        .iter()
+                        .map(|&index| self.weights[index] as u64)
+                        .sum::<u64>();
+
+                    if total == 0 {
+                        break;
+                    }
+
+                    let mut choice = rng.gen_range(0..total);
+
+                    for offset in start..len {
+                        let weight = self.weights[order[offset]] as u64;
+
+                        if choice < weight {
+                            order.swap(start, offset);
+                            break;
+                        }
+
+                        choice -= weight;
+                    }
+                }
+            }
+            Strategy::Fastest { reprobe_interval } => {
+                let mut stats = self.stats.lock().unwrap();
+
+                // Upstreams that haven't been measured yet sort first, so that they get measured
+                order.sort_by(|&a, &b| {
+                    let a = stats[a].average_rtt.unwrap_or(0.0);
+                    let b = stats[b].average_rtt.unwrap_or(0.0);
+                    a.total_cmp(&b)
+                });
+
+                let stale = order[1..].iter().position(|&index| {
+                    stats[index]
+                        .last_attempt
+                        .is_none_or(|last_attempt| last_attempt + reprobe_interval <= now)
+                });
+
+                if let Some(offset) = stale {
+                    order[0..offset + 2].rotate_right(1);
+                }
+
+                // Noting the attempt up front prevents every concurrent query from picking the same
+                // upstream to reprobe
+                stats[order[0]].last_attempt = Some(now);
+            }
+        }
+
+        order
+    }
+
+    // Records how long an upstream took to reply; failures should be recorded as taking the full
+    // timeout, so that unhealthy upstreams sort last
+    pub fn record(&self, index: usize, rtt: Duration, now: Instant) {
+        let mut stats = self.stats.lock().unwrap();
+        let stats = &mut stats[index];
+
+        let rtt = rtt.as_secs_f64();
+
+        stats.average_rtt = Some(match stats.average_rtt {
+            Some(average_rtt) => RTT_SMOOTHING * rtt + (1.0 - RTT_SMOOTHING) * average_rtt,
+            None => rtt,
+        });
+
+        stats.last_attempt = Some(now);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::upstream::{Selector, Strategy, Upstream};
+
+    use std::time::{Duration, Instant};
+
+    fn upstreams(weights: &[u32]) -> Vec<Upstream> {
+        weights
+            .iter()
+            .enumerate()
+            .map(|(index, &weight)| Upstream {
+                weight,
+                ..Upstream::new(format!("10.0.0.{}:53", index), Duration::from_secs(1))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let selector = Selector::new(Strategy::RoundRobin, &upstreams(&[1, 1, 1]));
+        let now = Instant::now();
+
+        assert_eq!(selector.order(now), [0, 1, 2]);
+        assert_eq!(selector.order(now), [1, 2, 0]);
+        assert_eq!(selector.order(now), [2, 0, 1]);
+        assert_eq!(selector.order(now), [0, 1, 2]);
+    }
+
+    #[test]
+    fn test_weighted_random() {
+        let selector = Selector::new(Strategy::WeightedRandom, &upstreams(&[0, 1, 0]));
+
+        for _ in 0..100 {
+            assert_eq!(selector.order(Instant::now())[0], 1);
+        }
+    }
+
+    #[test]
+    fn test_fastest() {
+        let reprobe_interval = Duration::from_secs(60);
+        let selector = Selector::new(
+            Strategy::Fastest { reprobe_interval },
+            &upstreams(&[1, 1, 1]),
+        );
+
+        let now = Instant::now();
+
+        selector.record(0, Duration::from_millis(50), now);
+        selector.record(1, Duration::from_millis(10), now);
+        selector.record(2, Duration::from_millis(30), now);
+
+        assert_eq!(selector.order(now), [1, 2, 0]);
+
+        // A failure pushes the fastest upstream's average up
+        selector.record(1, Duration::from_secs(1), now);
+        assert_eq!(selector.order(now), [2, 0, 1]);
+
+        // Once the reprobe interval has passed, the slower upstreams get a turn each
+        let later = now + reprobe_interval;
+        selector.record(2, Duration::from_millis(30), later);
+        assert_eq!(selector.order(later), [0, 2, 1]);
+        assert_eq!(selector.order(later), [1, 2, 0]);
+        assert_eq!(selector.order(later), [2, 0, 1]);
+    }
+}
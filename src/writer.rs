@@ -0,0 +1,184 @@
+use std::io::Error as IoError;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{
+    sync_channel, Receiver, RecvTimeoutError, SyncSender, TryRecvError, TrySendError,
+};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How many items may be waiting to be written before new ones are discarded
+const QUEUE_LENGTH: usize = 10000;
+
+// How often to try again to queue the command to close a writer whose queue is full
+const CLOSE_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+// Where a `Writer` sends its items. Sinks report their own errors, as only they know what was being
+// written to
+pub trait Sink: Send + 'static {
+    type Item: Send + 'static;
+
+    fn write(&mut self, item: Self::Item);
+
+    fn flush(&mut self);
+
+    // Called once the writer is closed or dropped, after which nothing more is written
+    fn finish(&mut self) {
+        self.flush();
+    }
+}
+
+enum Command<T> {
+    Write(T),
+    Close(SyncSender<()>),
+}
+
+// Writes items to a sink from a dedicated thread, so that answering queries never waits on the
+// output. If the thread falls behind, new items are discarded rather than queued without bound
+pub struct Writer<T> {
+    sender: SyncSender<Command<T>>,
+    discarded: AtomicU64,
+}
+
+impl<T: Send + 'static> Writer<T> {
+    pub fn spawn<S: Sink<Item = T>>(name: &str, sink: S) -> Result<Self, IoError> {
+        let (sender, receiver) = sync_channel(QUEUE_LENGTH);
+
+        thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || run(sink, receiver))?;
+
+        Ok(Self {
+            sender,
+            discarded: AtomicU64::new(0),
+        })
+    }
+
+    pub fn write(&self, item: T) {
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(Command::Write(item)) {
+            self.discarded.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // The number of items discarded so far because the thread had fallen behind
+    pub fn discarded(&self) -> u64 {
+        self.discarded.load(Ordering::Relaxed)
+    }
+
+    // Blocks until every item written so far has reached the sink, then finishes it. Returns false
+    // if that takes longer than the timeout, whereupon the thread is left to finish on its own
+    pub fn close(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let (sender, receiver) = sync_channel(1);
+        let mut command = Command::Close(sender);
+
+        // The queue may be full, and a thread that's stuck may never make room in it
+        loop {
+            match self.sender.try_send(command) {
+                Ok(()) => break,
+                Err(TrySendError::Full(rejected)) if Instant::now() < deadline => {
+                    command = rejected;
+                    thread::sleep(CLOSE_RETRY_INTERVAL);
+                }
+                Err(TrySendError::Full(_)) => return false,
+                Err(TrySendError::Disconnected(_)) => return true,
+            }
+        }
+
+        match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(()) | Err(RecvTimeoutError::Disconnected) => true,
+            Err(RecvTimeoutError::Timeout) => false,
+        }
+    }
+}
+
+// Writes items until the writer is closed or dropped. The sink is flushed whenever the queue
+// empties, so items reach it promptly without a write for each one
+fn run<S: Sink>(mut sink: S, receiver: Receiver<Command<S::Item>>) {
+    loop {
+        let command = match receiver.try_recv() {
+            Ok(command) => command,
+            Err(TryRecvError::Empty) => {
+                sink.flush();
+
+                match receiver.recv() {
+                    Ok(command) => command,
+                    Err(_) => break,
+                }
+            }
+            Err(TryRecvError::Disconnected) => break,
+        };
+
+        match command {
+            Command::Write(item) => sink.write(item),
+            Command::Close(done) => {
+                sink.finish();
+                let _ = done.send(());
+                return;
+            }
+        }
+    }
+
+    sink.finish();
+}
+
+#[cfg(test)]
+mod test {
+    use crate::writer::{Sink, Writer, QUEUE_LENGTH};
+
+    use std::sync::mpsc::{channel, Receiver, Sender};
+    use std::time::{Duration, Instant};
+
+    // Passes on what it's given, then stalls until it's released
+    struct Stalled {
+        release: Receiver<()>,
+        released: bool,
+        written: Sender<usize>,
+    }
+
+    impl Sink for Stalled {
+        type Item = usize;
+
+        fn write(&mut self, item: usize) {
+            let _ = self.written.send(item);
+            if !self.released {
+                self.released = self.release.recv().is_ok();
+            }
+        }
+
+        fn flush(&mut self) {}
+    }
+
+    #[test]
+    fn test_close_timeout() {
+        let (release, release_receiver) = channel();
+        let (written_sender, written) = channel();
+
+        let sink = Stalled {
+            release: release_receiver,
+            released: false,
+            written: written_sender,
+        };
+        let writer = Writer::spawn("test", sink).unwrap();
+
+        writer.write(0);
+        assert_eq!(written.recv().unwrap(), 0);
+
+        // The sink is stuck, so the queue fills up, leaving no room to close the writer
+        for item in 1..=QUEUE_LENGTH + 1 {
+            writer.write(item);
+        }
+        assert_eq!(writer.discarded(), 1);
+
+        let start = Instant::now();
+        assert!(!writer.close(Duration::from_millis(100)));
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // Once the sink is released, everything queued is written before the writer closes
+        release.send(()).unwrap();
+        assert!(writer.close(Duration::from_secs(5)));
+        assert_eq!(
+            written.try_iter().collect::<Vec<_>>(),
+            (1..=QUEUE_LENGTH).collect::<Vec<_>>()
+        );
+    }
+}